@@ -0,0 +1,19 @@
+-- Your SQL goes here
+CREATE TABLE sensor_points
+(
+    id SERIAL PRIMARY KEY,
+    device_id INTEGER references devices,
+    point_id VARCHAR NOT NULL,
+    slave_addr VARCHAR,
+    slave_index VARCHAR,
+    kind VARCHAR NOT NULL,
+    scale NUMERIC(10,4) NOT NULL DEFAULT 1,
+    value_offset NUMERIC(10,4) NOT NULL DEFAULT 0
+);
+-- NULL device_id/slave_addr/slave_index match any device or slave
+CREATE UNIQUE INDEX sensor_points_key ON sensor_points
+    (COALESCE(device_id,0), point_id, COALESCE(slave_addr,''), COALESCE(slave_index,''));
+
+INSERT INTO sensor_points (point_id, kind) VALUES ('44266', 'rain');
+INSERT INTO sensor_points (point_id, kind)
+    SELECT p::VARCHAR, 'depth' FROM generate_series(44271, 44399, 4) AS p;
@@ -0,0 +1,4 @@
+-- This file should undo anything in `up.sql`
+ALTER TABLE rainfalls DROP counter_event;
+ALTER TABLE rainfalls DROP counter;
+DROP TABLE rain_counters;
\ No newline at end of file
@@ -0,0 +1,9 @@
+-- Your SQL goes here
+CREATE TABLE rain_counters
+(
+    device_id INTEGER PRIMARY KEY references devices,
+    counter NUMERIC(10,2) NOT NULL,
+    update_time TIMESTAMP(0) WITH TIME ZONE NOT NULL
+);
+ALTER TABLE rainfalls ADD counter NUMERIC(10,2);
+ALTER TABLE rainfalls ADD counter_event VARCHAR NOT NULL DEFAULT 'normal';
@@ -0,0 +1,4 @@
+-- This file should undo anything in `up.sql`
+ALTER TABLE rainfalls DROP receive_time;
+ALTER TABLE water_depths DROP receive_time;
+ALTER TABLE calculations DROP receive_time;
\ No newline at end of file
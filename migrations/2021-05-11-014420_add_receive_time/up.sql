@@ -0,0 +1,12 @@
+-- Your SQL goes here
+ALTER TABLE rainfalls ADD receive_time TIMESTAMP(0) WITH TIME ZONE;
+UPDATE rainfalls SET receive_time = create_time;
+ALTER TABLE rainfalls ALTER receive_time SET NOT NULL;
+
+ALTER TABLE water_depths ADD receive_time TIMESTAMP(0) WITH TIME ZONE;
+UPDATE water_depths SET receive_time = create_time;
+ALTER TABLE water_depths ALTER receive_time SET NOT NULL;
+
+ALTER TABLE calculations ADD receive_time TIMESTAMP(0) WITH TIME ZONE;
+UPDATE calculations SET receive_time = create_time;
+ALTER TABLE calculations ALTER receive_time SET NOT NULL;
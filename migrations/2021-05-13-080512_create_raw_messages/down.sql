@@ -0,0 +1,3 @@
+-- This file should undo anything in `up.sql`
+DROP TABLE dead_letters;
+DROP TABLE raw_messages;
\ No newline at end of file
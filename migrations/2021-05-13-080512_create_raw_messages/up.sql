@@ -0,0 +1,18 @@
+-- Your SQL goes here
+CREATE TABLE raw_messages
+(
+    id BIGSERIAL PRIMARY KEY,
+    topic VARCHAR NOT NULL,
+    payload TEXT NOT NULL,
+    receive_time TIMESTAMP(0) WITH TIME ZONE NOT NULL
+);
+CREATE INDEX raw_messages_receive_time ON raw_messages (receive_time);
+
+CREATE TABLE dead_letters
+(
+    id SERIAL PRIMARY KEY,
+    topic VARCHAR NOT NULL,
+    payload TEXT NOT NULL,
+    reason VARCHAR NOT NULL,
+    receive_time TIMESTAMP(0) WITH TIME ZONE NOT NULL
+);
@@ -0,0 +1,3 @@
+-- This file should undo anything in `up.sql`
+DELETE FROM sensor_points WHERE device_id IS NULL AND point_id IN ('DRP','Z','VT');
+ALTER TABLE devices DROP sl651_address;
\ No newline at end of file
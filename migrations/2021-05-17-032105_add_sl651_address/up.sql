@@ -0,0 +1,8 @@
+-- Your SQL goes here
+ALTER TABLE devices ADD sl651_address VARCHAR;
+CREATE UNIQUE INDEX devices_sl651_address ON devices (sl651_address);
+
+-- DRP carries the 5-minute increments, a station without it needs a per-device PT counter point
+INSERT INTO sensor_points (point_id, kind) VALUES ('DRP', 'rain_delta');
+INSERT INTO sensor_points (point_id, kind) VALUES ('Z', 'depth');
+INSERT INTO sensor_points (point_id, kind) VALUES ('VT', 'voltage');
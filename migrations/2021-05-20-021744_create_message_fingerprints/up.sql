@@ -0,0 +1,9 @@
+-- Your SQL goes here
+CREATE TABLE message_fingerprints
+(
+    device_id INTEGER NOT NULL references devices,
+    fingerprint VARCHAR NOT NULL,
+    receive_time TIMESTAMP(0) WITH TIME ZONE NOT NULL,
+    PRIMARY KEY (device_id, fingerprint)
+);
+CREATE INDEX message_fingerprints_receive_time ON message_fingerprints (receive_time);
@@ -0,0 +1,3 @@
+-- This file should undo anything in `up.sql`
+DROP TABLE device_status;
+ALTER TABLE devices DROP offline_after;
\ No newline at end of file
@@ -0,0 +1,13 @@
+-- Your SQL goes here
+ALTER TABLE devices ADD offline_after INTEGER;
+
+CREATE TABLE device_status
+(
+    device_id INTEGER PRIMARY KEY references devices,
+    online BOOLEAN NOT NULL DEFAULT TRUE,
+    last_seen TIMESTAMP(0) WITH TIME ZONE NOT NULL,
+    last_payload_size INTEGER,
+    last_values TEXT NOT NULL DEFAULT '{}',
+    offline_reason VARCHAR,
+    status_time TIMESTAMP(0) WITH TIME ZONE NOT NULL
+);
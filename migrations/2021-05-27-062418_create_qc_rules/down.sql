@@ -0,0 +1,5 @@
+-- This file should undo anything in `up.sql`
+ALTER TABLE calculations DROP quality;
+ALTER TABLE water_depths DROP quality;
+ALTER TABLE rainfalls DROP quality;
+DROP TABLE qc_rules;
\ No newline at end of file
@@ -0,0 +1,22 @@
+-- Your SQL goes here
+CREATE TABLE qc_rules
+(
+    id SERIAL PRIMARY KEY,
+    device_id INTEGER references devices,
+    kind VARCHAR NOT NULL,
+    min_value NUMERIC(10,2),
+    max_value NUMERIC(10,2),
+    max_rate NUMERIC(10,2),
+    flatline_secs INTEGER
+);
+-- NULL device_id is the default rule of the kind; max_rate is the largest change per hour,
+-- flatline_secs how long a depth above min_value may repeat the same value
+CREATE UNIQUE INDEX qc_rules_key ON qc_rules (COALESCE(device_id,0), kind);
+
+INSERT INTO qc_rules (kind, min_value, max_rate) VALUES ('rain', 0, 200);
+INSERT INTO qc_rules (kind, min_value, flatline_secs) VALUES ('depth', 0, 86400);
+
+-- 0 good, 1 out of range, 2 rate of change, 3 flat line
+ALTER TABLE rainfalls ADD quality SMALLINT NOT NULL DEFAULT 0;
+ALTER TABLE water_depths ADD quality SMALLINT NOT NULL DEFAULT 0;
+ALTER TABLE calculations ADD quality SMALLINT NOT NULL DEFAULT 0;
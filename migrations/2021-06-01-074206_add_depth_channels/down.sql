@@ -0,0 +1,5 @@
+-- This file should undo anything in `up.sql`
+DROP INDEX water_depths_device_channel;
+ALTER TABLE devices DROP primary_channel;
+ALTER TABLE water_depths DROP channel;
+ALTER TABLE sensor_points DROP channel;
\ No newline at end of file
@@ -0,0 +1,7 @@
+-- Your SQL goes here
+-- NULL channel: the point id names the channel
+ALTER TABLE sensor_points ADD channel VARCHAR;
+ALTER TABLE water_depths ADD channel VARCHAR NOT NULL DEFAULT '';
+-- NULL primary_channel: every channel counts for flow and warnings
+ALTER TABLE devices ADD primary_channel VARCHAR;
+CREATE INDEX water_depths_device_channel ON water_depths (device_id, channel, create_time);
@@ -0,0 +1,3 @@
+-- This file should undo anything in `up.sql`
+DROP TABLE health_thresholds;
+DROP TABLE device_health;
\ No newline at end of file
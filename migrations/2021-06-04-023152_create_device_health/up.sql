@@ -0,0 +1,28 @@
+-- Your SQL goes here
+CREATE TABLE device_health
+(
+    id BIGSERIAL PRIMARY KEY,
+    device_id INTEGER NOT NULL references devices,
+    metric VARCHAR NOT NULL,
+    value NUMERIC(10,2) NOT NULL,
+    alarm BOOLEAN NOT NULL DEFAULT FALSE,
+    create_time TIMESTAMP(0) WITH TIME ZONE NOT NULL,
+    half_hour BIGINT NOT NULL,
+    receive_time TIMESTAMP(0) WITH TIME ZONE NOT NULL
+);
+CREATE INDEX device_health_device_metric ON device_health (device_id, metric, create_time);
+
+-- NULL device_id is the default threshold of the metric
+CREATE TABLE health_thresholds
+(
+    id SERIAL PRIMARY KEY,
+    device_id INTEGER references devices,
+    metric VARCHAR NOT NULL,
+    min_value NUMERIC(10,2),
+    max_value NUMERIC(10,2)
+);
+CREATE UNIQUE INDEX health_thresholds_key ON health_thresholds (COALESCE(device_id,0), metric);
+
+INSERT INTO health_thresholds (metric, min_value) VALUES ('voltage', 11.5);
+INSERT INTO health_thresholds (metric, min_value) VALUES ('signal', 10);
+INSERT INTO health_thresholds (metric, max_value) VALUES ('temperature', 65);
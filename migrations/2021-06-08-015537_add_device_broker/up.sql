@@ -0,0 +1,3 @@
+-- Your SQL goes here
+-- name of the [[brokers]] entry the device publishes to, NULL: the first broker
+ALTER TABLE devices ADD broker VARCHAR;
@@ -0,0 +1,3 @@
+-- This file should undo anything in `up.sql`
+DROP TABLE rating_points;
+DROP TABLE rating_curves;
\ No newline at end of file
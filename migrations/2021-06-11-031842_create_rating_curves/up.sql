@@ -0,0 +1,30 @@
+-- Your SQL goes here
+-- stage in metres, discharge in m3/s; stages outside min_stage..max_stage get no flow
+CREATE TABLE rating_curves
+(
+    id SERIAL PRIMARY KEY,
+    device_id INTEGER NOT NULL UNIQUE references devices,
+    curve_type VARCHAR NOT NULL,
+    coefficient NUMERIC(10,4),
+    exponent NUMERIC(10,4),
+    width NUMERIC(10,2),
+    side_slope NUMERIC(10,4),
+    notch_angle NUMERIC(10,2),
+    stage_offset NUMERIC(10,3) NOT NULL DEFAULT 0,
+    min_stage NUMERIC(10,3),
+    max_stage NUMERIC(10,3)
+);
+
+-- surveyed stage-discharge pairs of 'table' curves
+CREATE TABLE rating_points
+(
+    id SERIAL PRIMARY KEY,
+    curve_id INTEGER NOT NULL references rating_curves ON DELETE CASCADE,
+    stage NUMERIC(10,3) NOT NULL,
+    discharge NUMERIC(12,3) NOT NULL
+);
+CREATE UNIQUE INDEX rating_points_curve_stage ON rating_points (curve_id, stage);
+
+-- the formula used so far: rectangular weir with coefficient 1.5 over the stream width
+INSERT INTO rating_curves (device_id, curve_type, coefficient, width)
+SELECT id, 'rectangular', 1.5, stream_width FROM devices WHERE stream_width IS NOT NULL;
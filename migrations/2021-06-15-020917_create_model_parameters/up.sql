@@ -0,0 +1,13 @@
+-- Your SQL goes here
+-- runoff model parameters per device; devices without a row use the defaults,
+-- a NULL catchment_area falls back to devices.rainfall_area
+CREATE TABLE model_parameters
+(
+    device_id INTEGER PRIMARY KEY references devices,
+    catchment_area NUMERIC(10,3) CHECK (catchment_area > 0),
+    storage_capacity NUMERIC(10,2) NOT NULL DEFAULT 30 CHECK (storage_capacity >= 0),
+    loss_rate NUMERIC(10,3) NOT NULL DEFAULT 3 CHECK (loss_rate >= 0),
+    reservoir_a NUMERIC(12,6) NOT NULL DEFAULT 0.00045 CHECK (reservoir_a > 0),
+    reservoir_b NUMERIC(10,4) NOT NULL DEFAULT 0.7 CHECK (reservoir_b >= 0),
+    update_time TIMESTAMP(0) WITH TIME ZONE NOT NULL DEFAULT now()
+);
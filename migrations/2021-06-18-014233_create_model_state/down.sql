@@ -0,0 +1,3 @@
+-- This file should undo anything in `up.sql`
+DROP TABLE model_checkpoints;
+DROP TABLE model_state;
\ No newline at end of file
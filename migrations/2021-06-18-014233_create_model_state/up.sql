@@ -0,0 +1,33 @@
+-- Your SQL goes here
+-- the runoff model state each device continues from
+CREATE TABLE model_state
+(
+    device_id INTEGER PRIMARY KEY references devices,
+    storage NUMERIC(6,2) NOT NULL,
+    wi NUMERIC(6,2) NOT NULL,
+    state_time TIMESTAMP(0) WITH TIME ZONE NOT NULL,
+    calculation_id INTEGER,
+    update_time TIMESTAMP(0) WITH TIME ZONE NOT NULL
+);
+
+-- every state written, reason is calculation, recompute, restore or migrated
+CREATE TABLE model_checkpoints
+(
+    id BIGSERIAL PRIMARY KEY,
+    device_id INTEGER NOT NULL references devices,
+    storage NUMERIC(6,2) NOT NULL,
+    wi NUMERIC(6,2) NOT NULL,
+    state_time TIMESTAMP(0) WITH TIME ZONE NOT NULL,
+    calculation_id INTEGER,
+    reason VARCHAR NOT NULL,
+    restored_from BIGINT,
+    create_time TIMESTAMP(0) WITH TIME ZONE NOT NULL
+);
+CREATE INDEX model_checkpoints_device_time ON model_checkpoints (device_id, create_time);
+
+INSERT INTO model_state (device_id, storage, wi, state_time, calculation_id, update_time)
+SELECT DISTINCT ON (device_id) device_id, storage, wi, create_time, id, now()
+FROM calculations ORDER BY device_id, create_time DESC, id DESC;
+
+INSERT INTO model_checkpoints (device_id, storage, wi, state_time, calculation_id, reason, create_time)
+SELECT device_id, storage, wi, state_time, calculation_id, 'migrated', now() FROM model_state;
@@ -0,0 +1,7 @@
+-- This file should undo anything in `up.sql`
+ALTER TABLE model_checkpoints DROP COLUMN model;
+ALTER TABLE model_state DROP COLUMN model;
+ALTER TABLE calculations DROP COLUMN model;
+ALTER TABLE model_parameters DROP COLUMN runoff_coefficient;
+ALTER TABLE model_parameters DROP COLUMN curve_number;
+ALTER TABLE model_parameters DROP COLUMN runoff_model;
\ No newline at end of file
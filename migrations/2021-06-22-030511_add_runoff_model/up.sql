@@ -0,0 +1,9 @@
+-- Your SQL goes here
+-- runoff_model is reservoir, scs (curve number) or rational (runoff coefficient)
+ALTER TABLE model_parameters ADD COLUMN runoff_model VARCHAR NOT NULL DEFAULT 'reservoir';
+ALTER TABLE model_parameters ADD COLUMN curve_number NUMERIC(5,2) NOT NULL DEFAULT 75 CHECK (curve_number > 0 AND curve_number <= 100);
+ALTER TABLE model_parameters ADD COLUMN runoff_coefficient NUMERIC(4,3) NOT NULL DEFAULT 0.5 CHECK (runoff_coefficient >= 0 AND runoff_coefficient <= 1);
+
+ALTER TABLE calculations ADD COLUMN model VARCHAR NOT NULL DEFAULT 'reservoir';
+ALTER TABLE model_state ADD COLUMN model VARCHAR NOT NULL DEFAULT 'reservoir';
+ALTER TABLE model_checkpoints ADD COLUMN model VARCHAR NOT NULL DEFAULT 'reservoir';
@@ -0,0 +1,2 @@
+-- This file should undo anything in `up.sql`
+ALTER TABLE calculations ALTER COLUMN quantity TYPE NUMERIC(6,2);
\ No newline at end of file
@@ -0,0 +1,2 @@
+-- Your SQL goes here
+ALTER TABLE calculations ALTER COLUMN quantity TYPE NUMERIC(10,2);
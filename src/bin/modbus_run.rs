@@ -0,0 +1,18 @@
+use mountain_torrents::config::Config;
+use mountain_torrents::models::db_pool;
+use mountain_torrents::modbus::run_modbus_poller;
+use std::{env, process};
+
+// modbus_run [--once]
+fn main() {
+    let once = env::args().any(|a| a == "--once");
+    let config = Config::new();
+    let pool = db_pool(config.db_pool_size()).unwrap_or_else(|e| {
+        println!("{}", e.to_string());
+        process::exit(1);
+    });
+    if let Err(e) = run_modbus_poller(pool, once) {
+        println!("{}", e.to_string());
+        process::exit(1);
+    }
+}
@@ -1,5 +1,7 @@
-
 use mountain_torrents::mqtt_client::*;
+use mountain_torrents::config::Config;
+use mountain_torrents::models::db_pool;
+use std::process;
 //use chrono::prelude::*;
 
 
@@ -9,5 +11,13 @@ fn main() {
     //let local:DateTime<Local> = Local::now();
     //let navice_time = NaiveDateTime::from_timestamp((local.timestamp()+8*3600)/1800*1800, 0);  
     //println!("naive:{},utc:{}",navice_time.format("%Y-%m-%d %H:%M:%S"),utc.timestamp()) ;
-    run_mqtt_client();
-}
\ No newline at end of file
+    let config = Config::new();
+    let pool = db_pool(config.db_pool_size()).unwrap_or_else(|e| {
+        println!("{}", e.to_string());
+        process::exit(1);
+    });
+    if let Err(e) = run_mqtt_client(pool) {
+        println!("{}", e.to_string());
+        process::exit(1);
+    }
+}
@@ -0,0 +1,58 @@
+use chrono::prelude::*;
+use mountain_torrents::config::Config;
+use mountain_torrents::error::Error;
+use mountain_torrents::models::{self, db_pool};
+use mountain_torrents::replay;
+use std::{env, process};
+
+const USAGE:&str = "recompute_run DEV_ID --from DATE [--to DATE] [--dry-run]";
+
+fn run() -> Result<(),Error> {
+    let mut dev_id = None;
+    let mut from = None;
+    let mut to = None;
+    let mut dry_run = false;
+    let mut args = env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--dry-run" => dry_run = true,
+            "--from" | "--to" => {
+                let value = args.next().ok_or_else(|| Error::PayloadError(USAGE.to_string()))?;
+                if arg == "--from" {
+                    from = Some(replay::parse_bound(&value, false)?);
+                } else {
+                    to = Some(replay::parse_bound(&value, true)?);
+                }
+            }
+            _ => dev_id = Some(arg.parse::<i32>().map_err(|_| Error::PayloadError(USAGE.to_string()))?),
+        }
+    }
+    let (dev_id, from) = match (dev_id, from) {
+        (Some(d), Some(f)) => (d, f),
+        _ => return Err(Error::PayloadError(USAGE.to_string())),
+    };
+    let config = Config::new();
+    let pool = db_pool(config.db_pool_size().min(2))?;
+    let conn = pool.get().map_err(|e| Error::DatabaseError(e.to_string()))?;
+    let to = to.unwrap_or_else(|| Utc::now().timestamp());
+    let report = models::recompute_calculations(&conn, dev_id, from, to, dry_run)?;
+    for change in &report.changes {
+        println!("{}  {:?} -> {:?}", change.time, change.before, change.after);
+    }
+    println!("{}device {}: {} rainfalls, {} calculations deleted, {} inserted",
+        if dry_run { "Dry run: " } else { "" }, report.device_id, report.rainfalls, report.deleted, report.inserted);
+    println!("quantity total {} -> {}, peak {} -> {}", report.total_before, report.total_after, report.peak_before, report.peak_after);
+    if report.later_rows > 0 {
+        println!("{} calculations after the range were not recomputed", report.later_rows);
+    }
+    Ok(())
+}
+
+// recompute_run DEV_ID --from DATE [--to DATE] [--dry-run]
+// regenerates calculations from stored rainfalls, e.g. after model parameters changed
+fn main() {
+    if let Err(e) = run() {
+        println!("{}", e.to_string());
+        process::exit(1);
+    }
+}
@@ -0,0 +1,53 @@
+use mountain_torrents::config::Config;
+use mountain_torrents::error::Error;
+use mountain_torrents::models::db_pool;
+use mountain_torrents::replay::{self, ReplayOptions};
+use std::{env, process};
+
+const USAGE:&str = "replay_run [--dry-run] [--from DATE] [--to DATE] [--broker NAME] FILE...";
+
+fn run() -> Result<(),Error> {
+    let mut options = ReplayOptions {
+        dry_run:false,
+        from:None,
+        to:None,
+        broker:None,
+    };
+    let mut files = vec![];
+    let mut args = env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--dry-run" => options.dry_run = true,
+            "--from" | "--to" | "--broker" => {
+                let value = args.next().ok_or_else(|| Error::PayloadError(USAGE.to_string()))?;
+                match arg.as_str() {
+                    "--from" => options.from = Some(replay::parse_bound(&value, false)?),
+                    "--to" => options.to = Some(replay::parse_bound(&value, true)?),
+                    _ => options.broker = Some(value),
+                }
+            }
+            _ => files.push(arg),
+        }
+    }
+    if files.is_empty() {
+        return Err(Error::PayloadError(USAGE.to_string()));
+    }
+    let mut records = vec![];
+    for file in &files {
+        records.extend(replay::read_records(file)?);
+    }
+    let config = Config::new();
+    let pool = db_pool(1)?;
+    let summary = replay::replay(&pool, &config, records, &options)?;
+    println!("{}{:?}", if options.dry_run { "Dry run: " } else { "" }, summary);
+    Ok(())
+}
+
+// replay_run [--dry-run] [--from DATE] [--to DATE] [--broker NAME] FILE...
+// re-ingests JSON Lines or CSV dumps of raw messages with their original receive times
+fn main() {
+    if let Err(e) = run() {
+        println!("{}", e.to_string());
+        process::exit(1);
+    }
+}
@@ -0,0 +1,16 @@
+use mountain_torrents::config::Config;
+use mountain_torrents::models::db_pool;
+use mountain_torrents::sl651::run_sl651_listener;
+use std::process;
+
+fn main() {
+    let config = Config::new();
+    let pool = db_pool(config.db_pool_size()).unwrap_or_else(|e| {
+        println!("{}", e.to_string());
+        process::exit(1);
+    });
+    if let Err(e) = run_sl651_listener(pool) {
+        println!("{}", e.to_string());
+        process::exit(1);
+    }
+}
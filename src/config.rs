@@ -0,0 +1,441 @@
+use toml;
+use std::fs::File;
+use std::io::Read;
+use serde_derive::Deserialize;
+
+#[derive(Debug,Deserialize)]
+pub struct Config {
+    qos:Option<i32>,
+    mqtt_host:Option<String>,
+    client_id:Option<String>,
+    user_name:Option<String>,
+    password:Option<String>,
+    password_mode:Option<String>,
+    mqtt_version:Option<u32>,
+    keep_alive_secs:Option<u64>,
+    clean_session:Option<bool>,
+    ca_file:Option<String>,
+    client_cert:Option<String>,
+    client_key:Option<String>,
+    client_key_password:Option<String>,
+    point_reload_secs:Option<u64>,
+    max_reading_age_secs:Option<i64>,
+    rain_counter_max:Option<f32>,
+    db_pool_size:Option<u32>,
+    device_refresh_secs:Option<u64>,
+    archive_retention_days:Option<i64>,
+    checkpoint_retention_days:Option<i64>,
+    duplicate_window_secs:Option<i64>,
+    ingest_token:Option<String>,
+    admin_token:Option<String>,
+    sl651_listen:Option<String>,
+    modbus:Option<Vec<ModbusSource>>,
+    watchdog_secs:Option<u64>,
+    shutdown_timeout_secs:Option<u64>,
+    offline_after_secs:Option<i64>,
+    ingest_workers:Option<usize>,
+    queue_capacity:Option<usize>,
+    queue_policy:Option<String>,
+    lost_message_file:Option<String>,
+    lwt_topic:Option<String>,
+    topic:Option<String>,
+    topics:Option<Vec<String>>,
+    payload_format:Option<String>,
+    brokers:Option<Vec<BrokerConfig>>,
+}
+
+impl Config {
+    pub fn new() -> Self {
+        deser_toml()
+    }
+
+    // the [[brokers]] list, or a single broker from the top-level mqtt settings
+    pub fn brokers(&self) -> Vec<BrokerConfig> {
+        match self.brokers.as_ref() {
+            Some(b) if !b.is_empty() => b.clone(),
+            _ => vec![self.default_broker()],
+        }
+    }
+
+    fn default_broker(&self) -> BrokerConfig {
+        BrokerConfig {
+            name:None,
+            qos:self.qos,
+            mqtt_host:self.mqtt_host.clone(),
+            client_id:self.client_id.clone(),
+            user_name:self.user_name.clone(),
+            password:self.password.clone(),
+            password_mode:self.password_mode.clone(),
+            mqtt_version:self.mqtt_version,
+            keep_alive_secs:self.keep_alive_secs,
+            clean_session:self.clean_session,
+            ca_file:self.ca_file.clone(),
+            client_cert:self.client_cert.clone(),
+            client_key:self.client_key.clone(),
+            client_key_password:self.client_key_password.clone(),
+            lwt_topic:self.lwt_topic.clone(),
+            topic:self.topic.clone(),
+            topics:self.topics.clone(),
+            payload_format:self.payload_format.clone(),
+        }
+    }
+
+    // readings stamped further back than this are filed at their receive time
+    pub fn max_reading_age_secs(&self) -> i64 {
+        match self.max_reading_age_secs {
+            Some(s) => s.max(0),
+            None => 30*24*3600,
+        }
+    }
+
+    pub fn point_reload_secs(&self) -> u64 {
+        match self.point_reload_secs {
+            Some(s) => s,
+            None => 60,
+        }
+    }
+
+    pub fn rain_counter_max(&self) -> Option<f32> {
+        self.rain_counter_max
+    }
+
+    pub fn db_pool_size(&self) -> u32 {
+        match self.db_pool_size {
+            Some(s) => s.max(1),
+            None => 10,
+        }
+    }
+
+    pub fn device_refresh_secs(&self) -> u64 {
+        match self.device_refresh_secs {
+            Some(s) => s.max(1),
+            None => 60,
+        }
+    }
+
+    pub fn archive_retention_days(&self) -> i64 {
+        match self.archive_retention_days {
+            Some(d) => d,
+            None => 30,
+        }
+    }
+
+    pub fn checkpoint_retention_days(&self) -> i64 {
+        match self.checkpoint_retention_days {
+            Some(d) => d,
+            None => 30,
+        }
+    }
+
+    // readings without a device timestamp are duplicates only when the same payload repeats within this window;
+    // it only covers QoS redelivery, so keep it well below the shortest report interval
+    pub fn duplicate_window_secs(&self) -> i64 {
+        match self.duplicate_window_secs {
+            Some(s) => s.max(0),
+            None => 30,
+        }
+    }
+
+    pub fn ingest_token(&self) -> Option<&str> {
+        self.ingest_token.as_ref().map(|t| t.as_str())
+    }
+
+    // model parameters, recomputes and state restores; never given to field gateways
+    pub fn admin_token(&self) -> Option<&str> {
+        self.admin_token.as_ref().map(|t| t.as_str())
+    }
+
+    pub fn sl651_listen(&self) -> &str {
+        match self.sl651_listen.as_ref() {
+            Some(l) => &l,
+            None => "0.0.0.0:5005",
+        }
+    }
+
+    pub fn modbus(&self) -> &[ModbusSource] {
+        match self.modbus.as_ref() {
+            Some(m) => &m,
+            None => &[],
+        }
+    }
+
+    // reconnect when no message arrived for this long, 0 disables the watchdog
+    pub fn watchdog_secs(&self) -> u64 {
+        match self.watchdog_secs {
+            Some(s) => s,
+            None => 600,
+        }
+    }
+
+    pub fn shutdown_timeout_secs(&self) -> u64 {
+        match self.shutdown_timeout_secs {
+            Some(s) => s,
+            None => 10,
+        }
+    }
+
+    // devices.offline_after overrides this per device
+    pub fn offline_after_secs(&self) -> i64 {
+        match self.offline_after_secs {
+            Some(s) => s,
+            None => 1800,
+        }
+    }
+
+    pub fn ingest_workers(&self) -> usize {
+        match self.ingest_workers {
+            Some(w) => w.max(1),
+            None => 4,
+        }
+    }
+
+    // messages waiting per worker
+    pub fn queue_capacity(&self) -> usize {
+        match self.queue_capacity {
+            Some(c) => c.max(1),
+            None => 1000,
+        }
+    }
+
+    // "block" holds up the broker callback while the queue is full, "drop" discards the message
+    pub fn queue_policy(&self) -> &str {
+        match self.queue_policy.as_ref() {
+            Some(p) => &p,
+            None => "block",
+        }
+    }
+
+    // messages that couldn't be stored before shutdown, in the JSON Lines format replay_run reads
+    pub fn lost_message_file(&self) -> &str {
+        match self.lost_message_file.as_ref() {
+            Some(f) => &f,
+            None => "lost_messages.jsonl",
+        }
+    }
+    
+}
+
+// one [[brokers]] table per MQTT connection; devices.broker assigns devices by name
+#[derive(Debug,Deserialize,Clone)]
+pub struct BrokerConfig {
+    name:Option<String>,
+    qos:Option<i32>,
+    mqtt_host:Option<String>,
+    client_id:Option<String>,
+    user_name:Option<String>,
+    password:Option<String>,
+    password_mode:Option<String>,
+    mqtt_version:Option<u32>,
+    keep_alive_secs:Option<u64>,
+    clean_session:Option<bool>,
+    ca_file:Option<String>,
+    client_cert:Option<String>,
+    client_key:Option<String>,
+    client_key_password:Option<String>,
+    lwt_topic:Option<String>,
+    topic:Option<String>,
+    topics:Option<Vec<String>>,
+    payload_format:Option<String>,
+}
+
+impl BrokerConfig {
+    pub fn name(&self) -> &str {
+        match self.name.as_ref() {
+            Some(n) => &n,
+            None => "default",
+        }
+    }
+
+    pub fn qos(&self) -> i32 {
+        match self.qos {
+            Some(q) => q,
+            None => 1,
+        }
+    }
+    
+    pub fn host(&self) -> &str {
+        match self.mqtt_host.as_ref() {
+            Some(h) => &h,
+            None => "",
+        }
+    }
+
+    pub fn client_id(&self) -> &str {
+        match self.client_id.as_ref() {
+            Some(id) => &id,
+            None => "",
+        }
+    }
+
+    pub fn user_name(&self) -> &str {
+        match self.user_name.as_ref() {
+            Some(u) => &u,
+            None => "",
+        }
+    }
+
+    pub fn password(&self) -> &str {
+        match self.password.as_ref() {
+            Some(p) => &p,
+            None => "",
+        }
+    }
+
+    // "md5" sends the MD5 hex digest of the password (vendor cloud), "plain" sends it as is
+    pub fn password_mode(&self) -> &str {
+        match self.password_mode.as_ref() {
+            Some(m) => &m,
+            None => "md5",
+        }
+    }
+
+    // 0 lets the client negotiate, 3 = 3.1, 4 = 3.1.1, 5 = 5.0
+    pub fn mqtt_version(&self) -> u32 {
+        match self.mqtt_version {
+            Some(v) => v,
+            None => 0,
+        }
+    }
+
+    pub fn keep_alive_secs(&self) -> u64 {
+        match self.keep_alive_secs {
+            Some(s) => s,
+            None => 60,
+        }
+    }
+
+    pub fn clean_session(&self) -> bool {
+        match self.clean_session {
+            Some(c) => c,
+            None => true,
+        }
+    }
+
+    pub fn ca_file(&self) -> Option<&str> {
+        self.ca_file.as_ref().map(|f| f.as_str())
+    }
+
+    pub fn client_cert(&self) -> Option<&str> {
+        self.client_cert.as_ref().map(|f| f.as_str())
+    }
+
+    pub fn client_key(&self) -> Option<&str> {
+        self.client_key.as_ref().map(|f| f.as_str())
+    }
+
+    pub fn client_key_password(&self) -> Option<&str> {
+        self.client_key_password.as_ref().map(|p| p.as_str())
+    }
+
+    // topic prefix of the devices' last will, the device id follows the prefix
+    pub fn lwt_topic(&self) -> Option<&str> {
+        self.lwt_topic.as_ref().map(|t| t.as_str())
+    }
+
+    // topic templates like "site/{region}/{device_id}/up", topics = [...] takes several
+    pub fn topics(&self) -> Vec<&str> {
+        match (self.topics.as_ref(), self.topic.as_ref()) {
+            (Some(t), _) if !t.is_empty() => t.iter().map(|t| t.as_str()).collect(),
+            (_, Some(t)) => vec![t.as_str()],
+            _ => vec!["$USR/DevJsonTx/{device_id}"],
+        }
+    }
+
+    // "json": the gateway dataPoints document, "flat": {"<point_id>": value, "time": ...}
+    pub fn payload_format(&self) -> &str {
+        match self.payload_format.as_ref() {
+            Some(f) => &f,
+            None => "json",
+        }
+    }
+}
+
+// one [[modbus]] table per polled station
+#[derive(Debug,Deserialize)]
+pub struct ModbusSource {
+    device_id:String,
+    host:String,
+    unit_id:Option<u8>,
+    interval_secs:Option<u64>,
+    word_order:Option<String>,
+    registers:Vec<ModbusRegister>,
+}
+
+impl ModbusSource {
+    pub fn device_id(&self) -> &str {
+        &self.device_id
+    }
+
+    pub fn host(&self) -> &str {
+        &self.host
+    }
+
+    pub fn unit_id(&self) -> u8 {
+        match self.unit_id {
+            Some(u) => u,
+            None => 1,
+        }
+    }
+
+    pub fn interval_secs(&self) -> u64 {
+        match self.interval_secs {
+            Some(s) => s.max(1),
+            None => 300,
+        }
+    }
+
+    pub fn registers(&self) -> &[ModbusRegister] {
+        &self.registers
+    }
+}
+
+#[derive(Debug,Deserialize)]
+pub struct ModbusRegister {
+    point_id:String,
+    function:Option<String>,
+    address:u16,
+    data_type:Option<String>,
+    word_order:Option<String>,
+}
+
+impl ModbusRegister {
+    pub fn point_id(&self) -> &str {
+        &self.point_id
+    }
+
+    pub fn function(&self) -> &str {
+        match self.function.as_ref() {
+            Some(f) => &f,
+            None => "holding",
+        }
+    }
+
+    pub fn address(&self) -> u16 {
+        self.address
+    }
+
+    pub fn data_type(&self) -> &str {
+        match self.data_type.as_ref() {
+            Some(t) => &t,
+            None => "uint16",
+        }
+    }
+
+    // falls back to the station's word order, then big endian (high word first)
+    pub fn word_order<'a>(&'a self,source:&'a ModbusSource) -> &'a str {
+        match (self.word_order.as_ref(), source.word_order.as_ref()) {
+            (Some(w), _) => &w,
+            (None, Some(w)) => &w,
+            (None, None) => "big",
+        }
+    }
+}
+
+fn deser_toml() -> Config {
+    let mut toml_str = String::new();
+    File::open("Config.toml")
+        .and_then(|mut f| f.read_to_string(&mut toml_str))
+        .unwrap();
+    
+    toml::from_str(&toml_str).unwrap()    
+}
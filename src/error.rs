@@ -4,6 +4,10 @@ pub enum Error {
     MqttError(String),
     WebError(String),
     ExcelError(String),
+    PayloadError(String),
+    ModbusError(String),
+    RatingError(String),
+    ModelError(String),
 }
 impl Error {
     pub fn to_string(&self) -> String {
@@ -12,6 +16,16 @@ impl Error {
             Self::MqttError(str) => format!("mqtt error {}",str),
             Self::WebError(str) => format!("web error {}",str),
             Self::ExcelError(str) => format!("excel error {}",str),
+            Self::PayloadError(str) => format!("payload error {}",str),
+            Self::ModbusError(str) => format!("modbus error {}",str),
+            Self::RatingError(str) => format!("rating error {}",str),
+            Self::ModelError(str) => format!("model error {}",str),
         }        
     }
+}
+
+impl From<diesel::result::Error> for Error {
+    fn from(e:diesel::result::Error) -> Error {
+        Error::DatabaseError(e.to_string())
+    }
 }
\ No newline at end of file
@@ -0,0 +1,415 @@
+use serde_derive::{Deserialize,Serialize};
+use serde_json;
+use chrono::prelude::*;
+use diesel::pg::PgConnection;
+use diesel::connection::Connection;
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use super::config::Config;
+use super::error::Error;
+use super::models::{self, ReadingTime};
+use super::points::{PointKind, PointRegistry};
+use super::qc::QcRegistry;
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct PayloadData {
+    #[serde(rename="dataPoints")]
+    data_points:Vec<DataPoint>,
+    #[serde(rename="devName")]
+    dev_name:String,
+    #[serde(default)]
+    time:Option<DeviceTime>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct DataPoint {
+    #[serde(rename="pointId")]
+    point_id:String,
+    value:String,
+    #[serde(rename="slaveIndex")]
+    slave_index:String,
+    #[serde(rename="slaveAddr")]
+    slave_addr:String,
+    #[serde(default)]
+    time:Option<DeviceTime>,
+}
+
+// gateways report either a unix stamp (seconds or milliseconds) or a local "%Y-%m-%d %H:%M:%S" string
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(untagged)]
+pub enum DeviceTime {
+    Stamp(i64),
+    Text(String),
+}
+
+impl DeviceTime {
+    pub fn timestamp(&self) -> Option<i64> {
+        let stamp = match self {
+            Self::Stamp(s) => *s,
+            Self::Text(t) => match t.parse::<i64>() {
+                Ok(s) => s,
+                Err(_) => {
+                    let local = NaiveDateTime::parse_from_str(t, "%Y-%m-%d %H:%M:%S").ok()?;
+                    return Some(local.timestamp()-8*3600);
+                }
+            },
+        };
+        let stamp = if stamp > 100_000_000_000 { stamp/1000 } else { stamp };
+        // beyond what a timestamp column holds
+        NaiveDateTime::from_timestamp_opt(stamp, 0).map(|_| stamp)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum PayloadFormat {
+    // {"dataPoints":[{"pointId":..,"value":..}],"devName":..}
+    Json,
+    // {"<point_id>":value,...,"time":..}
+    Flat,
+}
+
+impl PayloadFormat {
+    pub fn parse(format:&str) -> Result<PayloadFormat,Error> {
+        match format {
+            "json" => Ok(PayloadFormat::Json),
+            "flat" => Ok(PayloadFormat::Flat),
+            other => Err(Error::PayloadError(format!("unknown payload format {}", other))),
+        }
+    }
+}
+
+impl PayloadData {
+    pub fn parse(payload:&str) -> Result<PayloadData,Error> {
+        serde_json::from_str(payload)
+            .map_err(|e| Error::PayloadError(format!("Error parse payload to {}", e.to_string())))
+    }
+
+    pub fn parse_format(payload:&str,format:PayloadFormat) -> Result<PayloadData,Error> {
+        match format {
+            PayloadFormat::Json => PayloadData::parse(payload),
+            PayloadFormat::Flat => PayloadData::parse_flat(payload),
+        }
+    }
+
+    pub fn data_points(&self) -> &[DataPoint] {
+        &self.data_points
+    }
+
+    fn parse_flat(payload:&str) -> Result<PayloadData,Error> {
+        let object:serde_json::Map<String,serde_json::Value> = serde_json::from_str(payload)
+            .map_err(|e| Error::PayloadError(format!("Error parse payload to {}", e.to_string())))?;
+        let mut time = None;
+        let mut data_points = vec![];
+        for (key, value) in object {
+            if key == "time" {
+                time = serde_json::from_value::<DeviceTime>(value).ok();
+                continue;
+            }
+            let value = match value {
+                serde_json::Value::String(v) => v,
+                serde_json::Value::Number(n) => n.to_string(),
+                _ => continue,
+            };
+            data_points.push(DataPoint {
+                point_id:key,
+                value,
+                slave_index:"".to_string(),
+                slave_addr:"".to_string(),
+                time:None,
+            });
+        }
+        Ok(PayloadData {
+            data_points,
+            dev_name:"".to_string(),
+            time,
+        })
+    }
+}
+
+impl DataPoint {
+    pub fn new(point_id:&str,value:f32,time:Option<i64>) -> DataPoint {
+        DataPoint {
+            point_id:point_id.to_string(),
+            value:value.to_string(),
+            slave_index:"".to_string(),
+            slave_addr:"".to_string(),
+            time:time.map(DeviceTime::Stamp),
+        }
+    }
+    pub fn slave(mut self,slave_addr:&str,slave_index:&str) -> DataPoint {
+        self.slave_addr = slave_addr.to_string();
+        self.slave_index = slave_index.to_string();
+        self
+    }
+    fn get_value(&self) -> Option<f32> {
+        self.value.trim().parse::<f32>().ok().filter(|v| v.is_finite())
+    }
+    fn device_time(&self,payload_time:Option<i64>) -> Option<i64> {
+        self.time.as_ref().and_then(|t| t.timestamp()).or(payload_time)
+    }
+    fn reading_time(&self,payload_time:Option<i64>,received:i64,max_age_secs:i64) -> ReadingTime {
+        let device_time = self.device_time(payload_time);
+        let time = ReadingTime::new(device_time, received, max_age_secs);
+        if let Some(t) = device_time.filter(|t| *t != time.observed) {
+            println!("Device time {} of {} out of range, receive time used", t, self.point_id);
+        }
+        time
+    }
+    // device, point and device timestamp identify a reading for good; without a timestamp
+    // the payload hash only identifies a QoS redelivery inside the short duplicate window,
+    // an unchanged reading sent again on the next report is stored
+    fn fingerprint(&self,payload_time:Option<i64>,raw:Option<&str>,window_secs:i64) -> Option<(String,Option<i64>)> {
+        let key = format!("{}:{}:{}", self.point_id, self.slave_addr, self.slave_index);
+        match self.device_time(payload_time) {
+            Some(t) => Some((format!("{}@{}", key, t), None)),
+            None => raw.map(|r| (format!("{}#{:x}", key, md5::compute(r)), Some(window_secs))),
+        }
+    }
+}
+
+#[derive(Serialize, Debug)]
+pub struct IngestReport {
+    pub device_id:i32,
+    pub stored:usize,
+    pub duplicates:usize,
+    pub failed:usize,
+}
+
+// storage logic shared by every transport: archive, parse, map points, store readings
+pub struct Ingestor {
+    points:PointRegistry,
+    qc:QcRegistry,
+    rain_counter_max:Option<f32>,
+    max_reading_age_secs:i64,
+    duplicate_window_secs:i64,
+    duplicates:AtomicUsize,
+    replay:bool,
+    // replay: first and last rainfall time stored per device
+    replayed:Mutex<HashMap<i32,(i64,i64)>>,
+}
+
+impl Ingestor {
+    pub fn new(config:&Config) -> Ingestor {
+        Ingestor {
+            points:PointRegistry::new(config.point_reload_secs()),
+            qc:QcRegistry::new(config.point_reload_secs()),
+            rain_counter_max:config.rain_counter_max(),
+            max_reading_age_secs:config.max_reading_age_secs(),
+            duplicate_window_secs:config.duplicate_window_secs(),
+            duplicates:AtomicUsize::new(0),
+            replay:false,
+            replayed:Mutex::new(HashMap::new()),
+        }
+    }
+
+    // replayed messages are already archived and older than the device status, so neither is touched;
+    // rain counters and model state are the live ones, so rainfall is stored without calculations
+    pub fn replay(mut self) -> Ingestor {
+        self.replay = true;
+        self
+    }
+
+    // devices with replayed rainfall and the time range to recompute
+    pub fn replayed(&self) -> Vec<(i32,i64,i64)> {
+        let mut ranges:Vec<(i32,i64,i64)> = self.replayed.lock().unwrap().iter()
+            .map(|(dev_id, (from, to))| (*dev_id, *from, *to))
+            .collect();
+        ranges.sort();
+        ranges
+    }
+
+    fn replayed_rainfall(&self,dev_id:i32,time:ReadingTime) {
+        let mut replayed = self.replayed.lock().unwrap();
+        let range = replayed.entry(dev_id).or_insert((time.observed, time.observed));
+        range.0 = range.0.min(time.observed);
+        range.1 = range.1.max(time.observed);
+    }
+
+    // duplicates dropped since start
+    pub fn duplicates(&self) -> usize {
+        self.duplicates.load(Ordering::Relaxed)
+    }
+
+    // source is the MQTT topic or another transport label, kept with archived and rejected messages
+    pub fn ingest(&self,conn:&PgConnection,source:&str,device_key:&str,payload_str:&str,format:PayloadFormat,received:i64) -> Result<IngestReport,Error> {
+        if !self.replay {
+            if let Err(e) = models::new_raw_message(conn, source, payload_str, received) {
+                println!("{}", e.to_string());
+            }
+        }
+        let payload = match PayloadData::parse_format(payload_str, format) {
+            Ok(p) => p,
+            Err(e) => {
+                reject_message(conn, source, payload_str, &e.to_string(), received);
+                return Err(e);
+            }
+        };
+        let dev_id = match models::get_id_by_deviceid(conn, device_key.to_string()) {
+            Ok(d) => d,
+            Err(_) => {
+                let reason = format!("unknown device {}", device_key);
+                reject_message(conn, source, payload_str, &reason, received);
+                return Err(Error::PayloadError(reason));
+            }
+        };
+        let payload_time = payload.time.as_ref().and_then(|t| t.timestamp());
+        Ok(self.ingest_points(conn, dev_id, &payload.data_points, payload_time, Some(payload_str), received))
+    }
+
+    // stores the points that sensor_points maps for this device and drops redelivered ones;
+    // raw is the payload as received, used to fingerprint points without a device timestamp
+    pub fn ingest_points(&self,conn:&PgConnection,dev_id:i32,points:&[DataPoint],payload_time:Option<i64>,raw:Option<&str>,received:i64) -> IngestReport {
+        let mut report = IngestReport {
+            device_id:dev_id,
+            stored:0,
+            duplicates:0,
+            failed:0,
+        };
+        let values:serde_json::Map<String,serde_json::Value> = points.iter()
+            .map(|p| (p.point_id.clone(), serde_json::Value::from(p.get_value())))
+            .collect();
+        let values = serde_json::Value::Object(values).to_string();
+        if !self.replay {
+            if let Err(e) = models::touch_device_status(conn, dev_id, raw.map(|r| r.len() as i32), &values, received) {
+                println!("{}", e.to_string());
+            }
+        }
+        for point in points {
+            let mapping = self.points.lookup(conn, dev_id, &point.point_id, &point.slave_addr, &point.slave_index);
+            let mapping = match mapping {
+                Some(m) => m,
+                None => continue,
+            };
+            match mapping.kind {
+                PointKind::Rain | PointKind::RainDelta | PointKind::Depth => {}
+                ref k if k.health_metric().is_some() => {}
+                _ => continue,
+            }
+            let value = match point.get_value() {
+                Some(v) => mapping.apply(v),
+                None => {
+                    println!("Unparseable value {:?} of {} on device {}", point.value, point.point_id, dev_id);
+                    continue;
+                }
+            };
+            let time = point.reading_time(payload_time, received, self.max_reading_age_secs);
+            let fingerprint = point.fingerprint(payload_time, raw, self.duplicate_window_secs);
+            // the fingerprint is only kept when the reading was stored
+            let result = conn.transaction::<bool,Error,_>(|| {
+                if let Some((fp, window)) = fingerprint.as_ref() {
+                    if !models::claim_fingerprint(conn, dev_id, fp, received, *window)? {
+                        return Ok(false);
+                    }
+                }
+                match mapping.kind {
+                    PointKind::Rain => self.store_rainfall(conn, dev_id, value, time)?,
+                    PointKind::RainDelta => self.store_rain_increment(conn, dev_id, value, time)?,
+                    PointKind::Depth => {
+                        let channel = mapping.channel.as_ref().unwrap_or(&point.point_id);
+                        self.store_water_depth(conn, dev_id, channel, value, time)?
+                    }
+                    ref k => store_health(conn, dev_id, k.health_metric().unwrap_or_default(), value, time)?,
+                };
+                Ok(true)
+            });
+            match result {
+                Ok(true) => report.stored += 1,
+                Ok(false) => {
+                    report.duplicates += 1;
+                    self.duplicates.fetch_add(1, Ordering::Relaxed);
+                    println!("Duplicate {} on device {} dropped", point.point_id, dev_id);
+                }
+                Err(e) => {
+                    report.failed += 1;
+                    println!("{}", e.to_string());
+                }
+            }
+        }
+        report
+    }
+
+    fn store_rainfall(&self,conn:&PgConnection,dev_id:i32,counter:f32,time:ReadingTime) -> Result<usize,Error> {
+        // a replay continues from the counter stored just before it, not the live baseline
+        let last = if self.replay {
+            models::rain_counter_before(conn, dev_id, time.observed_time())?
+        } else {
+            models::rain_counter(conn, dev_id)?
+        };
+        let (rainfall_value, event) = models::rain_increment(last, counter, time.observed, self.rain_counter_max);
+        if event != models::CounterEvent::Normal {
+            println!("Rain counter {} on device {}: {:?} -> {}", event.as_str(), dev_id, last, counter);
+        }
+        let quality = self.qc.check(conn, dev_id, &PointKind::Rain, "", rainfall_value, time).code();
+        let count = models::new_rainfall(conn, dev_id, rainfall_value, Some(counter), event, time, quality)?;
+        if self.replay {
+            self.replayed_rainfall(dev_id, time);
+            return Ok(count);
+        }
+        // a late reading is kept for the record, the model has already stepped past it
+        if event != models::CounterEvent::Late {
+            models::new_calculation(conn, dev_id, rainfall_value, time, quality).unwrap_or_default();
+        }
+        if event.moves_baseline() {
+            models::save_rain_counter(conn, dev_id, counter, time)?;
+        }
+        Ok(count)
+    }
+
+    // readings that are already an increment, e.g. SL651 5-minute rainfall
+    fn store_rain_increment(&self,conn:&PgConnection,dev_id:i32,value:f32,time:ReadingTime) -> Result<usize,Error> {
+        let quality = self.qc.check(conn, dev_id, &PointKind::RainDelta, "", value, time).code();
+        let count = models::new_rainfall(conn, dev_id, value, None, models::CounterEvent::Increment, time, quality)?;
+        if self.replay {
+            self.replayed_rainfall(dev_id, time);
+            return Ok(count);
+        }
+        models::new_calculation(conn, dev_id, value, time, quality).unwrap_or_default();
+        Ok(count)
+    }
+
+    // channel defaults to the point id, so the gauges of one station stay apart
+    fn store_water_depth(&self,conn:&PgConnection,dev_id:i32,channel:&str,value:f32,time:ReadingTime) -> Result<usize,Error> {
+        let quality = self.qc.check(conn, dev_id, &PointKind::Depth, channel, value, time).code();
+        models::new_water_depth(conn, dev_id, channel, value, time, quality)
+    }
+}
+
+// low battery, weak signal or a hot enclosure raise the row's alarm flag
+fn store_health(conn:&PgConnection,dev_id:i32,metric:&str,value:f32,time:ReadingTime) -> Result<usize,Error> {
+    let alarm = match models::health_threshold(conn, dev_id, metric)? {
+        Some((min, max)) => min.map(|m| value < m).unwrap_or(false) || max.map(|m| value > m).unwrap_or(false),
+        None => false,
+    };
+    if alarm {
+        println!("Health alarm on device {}: {} {}", dev_id, metric, value);
+    }
+    models::new_device_health(conn, dev_id, metric, value, alarm, time)
+}
+
+pub fn reject_message(conn:&PgConnection,topic:&str,payload:&str,reason:&str,received:i64) {
+    println!("Rejected message on {}: {}", topic, reason);
+    if let Err(e) = models::new_dead_letter(conn, topic, payload, reason, received) {
+        println!("{}", e.to_string());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn device_time_stamps() {
+        assert_eq!(DeviceTime::Stamp(1_622_505_600).timestamp(), Some(1_622_505_600));
+        assert_eq!(DeviceTime::Stamp(1_622_505_600_000).timestamp(), Some(1_622_505_600));
+        assert_eq!(DeviceTime::Text("2021-06-01 08:00:00".to_string()).timestamp(), Some(1_622_505_600));
+        assert_eq!(DeviceTime::Text("yesterday".to_string()).timestamp(), None);
+    }
+
+    #[test]
+    fn unrepresentable_device_time_is_none() {
+        assert_eq!(DeviceTime::Stamp(-100_000_000_000_000).timestamp(), None);
+        assert_eq!(DeviceTime::Text("-100000000000000".to_string()).timestamp(), None);
+    }
+}
@@ -19,7 +19,18 @@ mod tests {
 pub mod schema;
 pub mod models;
 pub mod error;
+pub mod config;
+pub mod ingest;
 pub mod mqtt_client;
+pub mod topic;
+pub mod queue;
+pub mod points;
+pub mod qc;
+pub mod rating;
+pub mod runoff;
+pub mod sl651;
+pub mod modbus;
+pub mod replay;
 pub mod sum;
 pub mod web;
 
@@ -0,0 +1,247 @@
+use chrono::prelude::*;
+use serde_json;
+use std::io::{Read, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::sync::Arc;
+use std::{thread, time::Duration};
+
+use super::config::{Config, ModbusRegister, ModbusSource};
+use super::error::Error;
+use super::ingest::{self, DataPoint, Ingestor};
+use super::models::{self, PgPool};
+
+const FN_READ_HOLDING:u8 = 0x03;
+const FN_READ_INPUT:u8 = 0x04;
+const MBAP_LEN:usize = 7;
+const TIMEOUT_SECS:u64 = 10;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum DataType {
+    Int16,
+    Uint16,
+    Int32,
+    Uint32,
+    Float32,
+}
+
+impl DataType {
+    pub fn parse(data_type:&str) -> Result<DataType,Error> {
+        match data_type {
+            "int16" => Ok(DataType::Int16),
+            "uint16" => Ok(DataType::Uint16),
+            "int32" => Ok(DataType::Int32),
+            "uint32" => Ok(DataType::Uint32),
+            "float32" => Ok(DataType::Float32),
+            other => Err(Error::ModbusError(format!("unknown data type {}", other))),
+        }
+    }
+
+    pub fn register_count(&self) -> u16 {
+        match self {
+            Self::Int16 | Self::Uint16 => 1,
+            _ => 2,
+        }
+    }
+}
+
+// big: high word first (AB CD), little: low word first (CD AB)
+pub fn decode_registers(registers:&[u16],data_type:DataType,little_endian:bool) -> Option<f32> {
+    let word = |i:usize| registers.get(i).cloned();
+    let double = || -> Option<u32> {
+        let (high, low) = if little_endian { (word(1)?, word(0)?) } else { (word(0)?, word(1)?) };
+        Some((high as u32) << 16 | low as u32)
+    };
+    match data_type {
+        DataType::Int16 => word(0).map(|w| w as i16 as f32),
+        DataType::Uint16 => word(0).map(|w| w as f32),
+        DataType::Int32 => double().map(|d| d as i32 as f32),
+        DataType::Uint32 => double().map(|d| d as f32),
+        DataType::Float32 => double().map(f32::from_bits).filter(|v| v.is_finite()),
+    }
+}
+
+pub struct ModbusClient {
+    stream:TcpStream,
+    transaction:u16,
+}
+
+impl ModbusClient {
+    pub fn connect(host:&str) -> Result<ModbusClient,Error> {
+        let addr = host.to_socket_addrs().ok()
+            .and_then(|mut a| a.next())
+            .ok_or_else(|| Error::ModbusError(format!("Error resolve {}", host)))?;
+        let stream = TcpStream::connect_timeout(&addr, Duration::from_secs(TIMEOUT_SECS))
+            .map_err(|e| Error::ModbusError(format!("Error connect {} to {}", host, e)))?;
+        stream.set_read_timeout(Some(Duration::from_secs(TIMEOUT_SECS))).ok();
+        Ok(ModbusClient {
+            stream,
+            transaction:0,
+        })
+    }
+
+    pub fn read_registers(&mut self,unit_id:u8,function:u8,address:u16,count:u16) -> Result<Vec<u16>,Error> {
+        self.transaction = self.transaction.wrapping_add(1);
+        let mut request = vec![];
+        request.extend_from_slice(&self.transaction.to_be_bytes());
+        request.extend_from_slice(&[0, 0, 0, 6, unit_id, function]);
+        request.extend_from_slice(&address.to_be_bytes());
+        request.extend_from_slice(&count.to_be_bytes());
+        self.stream.write_all(&request)
+            .map_err(|e| Error::ModbusError(format!("Error send request to {}", e)))?;
+
+        let mut header = [0u8;MBAP_LEN];
+        self.stream.read_exact(&mut header)
+            .map_err(|e| Error::ModbusError(format!("Error read response to {}", e)))?;
+        let len = u16::from_be_bytes([header[4], header[5]]) as usize;
+        if u16::from_be_bytes([header[0], header[1]]) != self.transaction || len < 2 {
+            return Err(Error::ModbusError("Error response header".to_string()));
+        }
+        let mut pdu = vec![0u8;len-1];
+        self.stream.read_exact(&mut pdu)
+            .map_err(|e| Error::ModbusError(format!("Error read response to {}", e)))?;
+        if pdu[0] == function | 0x80 {
+            return Err(Error::ModbusError(format!("exception code {}", pdu.get(1).cloned().unwrap_or(0))));
+        }
+        let byte_count = pdu.get(1).cloned().unwrap_or(0) as usize;
+        if pdu[0] != function || byte_count != count as usize*2 || pdu.len() < 2+byte_count {
+            return Err(Error::ModbusError("Error response length".to_string()));
+        }
+        Ok(pdu[2..2+byte_count].chunks(2).map(|c| u16::from_be_bytes([c[0], c[1]])).collect())
+    }
+}
+
+fn read_point(client:&mut ModbusClient,source:&ModbusSource,register:&ModbusRegister) -> Result<DataPoint,Error> {
+    let function = match register.function() {
+        "holding" => FN_READ_HOLDING,
+        "input" => FN_READ_INPUT,
+        other => return Err(Error::ModbusError(format!("unknown register function {}", other))),
+    };
+    let data_type = DataType::parse(register.data_type())?;
+    let words = client.read_registers(source.unit_id(), function, register.address(), data_type.register_count())?;
+    let value = decode_registers(&words, data_type, register.word_order(source) == "little")
+        .ok_or_else(|| Error::ModbusError(format!("Error decode register {}", register.address())))?;
+    Ok(DataPoint::new(register.point_id(), value, None)
+        .slave(&source.unit_id().to_string(), &register.address().to_string()))
+}
+
+// reads every configured register of a station and stores the values through the ingestor
+pub fn poll_source(pool:&PgPool,ingestor:&Ingestor,source:&ModbusSource) -> Result<usize,Error> {
+    let received = Utc::now().timestamp();
+    let mut client = ModbusClient::connect(source.host())?;
+    let mut points = vec![];
+    for register in source.registers() {
+        match read_point(&mut client, source, register) {
+            Ok(p) => points.push(p),
+            Err(e) => {
+                println!("Read {} register {} failed: {}", source.host(), register.address(), e.to_string());
+                // a late or partial reply would be taken as the answer to the next request
+                client = match ModbusClient::connect(source.host()) {
+                    Ok(c) => c,
+                    Err(e) => {
+                        println!("Reconnect {} failed: {}", source.host(), e.to_string());
+                        break;
+                    }
+                };
+            }
+        }
+    }
+    // nothing read: the station isn't answering, so it is neither stored nor marked online
+    if points.is_empty() {
+        return Err(Error::ModbusError(format!("no register of {} read", source.host())));
+    }
+    let conn = pool.get().map_err(|e| Error::DatabaseError(e.to_string()))?;
+    let raw = serde_json::to_string(&points).unwrap_or_default();
+    let origin = format!("modbus/{}/{}", source.host(), source.unit_id());
+    if let Err(e) = models::new_raw_message(&conn, &origin, &raw, received) {
+        println!("{}", e.to_string());
+    }
+    let dev_id = match models::get_id_by_deviceid(&conn, source.device_id().to_string()) {
+        Ok(d) => d,
+        Err(_) => {
+            let reason = format!("unknown device {}", source.device_id());
+            ingest::reject_message(&conn, &origin, &raw, &reason, received);
+            return Err(Error::ModbusError(reason));
+        }
+    };
+    // every poll is a fresh reading, so there is no payload to fingerprint
+    Ok(ingestor.ingest_points(&conn, dev_id, &points, None, None, received).stored)
+}
+
+fn poll_loop(pool:PgPool,ingestor:Arc<Ingestor>,config:Arc<Config>,index:usize) {
+    let source = &config.modbus()[index];
+    loop {
+        match poll_source(&pool, &ingestor, source) {
+            Ok(stored) => println!("Polled {} stored {} points", source.host(), stored),
+            Err(e) => println!("Poll {} failed: {}", source.host(), e.to_string()),
+        }
+        thread::sleep(Duration::from_secs(source.interval_secs()));
+    }
+}
+
+// once: poll every station a single time and return, e.g. against a local simulator
+pub fn run_modbus_poller(pool:PgPool,once:bool) -> Result<(),Error> {
+    let config = Arc::new(Config::new());
+    let ingestor = Arc::new(Ingestor::new(&config));
+    if config.modbus().is_empty() {
+        return Err(Error::ModbusError("no [[modbus]] sources in Config.toml".to_string()));
+    }
+    if once {
+        for source in config.modbus() {
+            match poll_source(&pool, &ingestor, source) {
+                Ok(stored) => println!("Polled {} stored {} points", source.host(), stored),
+                Err(e) => println!("Poll {} failed: {}", source.host(), e.to_string()),
+            }
+        }
+        return Ok(());
+    }
+    let handles:Vec<_> = (0..config.modbus().len()).map(|index| {
+        let pool = pool.clone();
+        let ingestor = ingestor.clone();
+        let config = config.clone();
+        thread::spawn(move || poll_loop(pool, ingestor, config, index))
+    }).collect();
+    for handle in handles {
+        handle.join().ok();
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn decodes_single_registers() {
+        assert_eq!(decode_registers(&[0xFFFE], DataType::Int16, false), Some(-2.0));
+        assert_eq!(decode_registers(&[0xFFFE], DataType::Uint16, false), Some(65534.0));
+        assert_eq!(decode_registers(&[], DataType::Uint16, false), None);
+    }
+
+    #[test]
+    fn big_word_order_is_high_word_first() {
+        assert_eq!(decode_registers(&[0x0001, 0x0000], DataType::Uint32, false), Some(65536.0));
+        assert_eq!(decode_registers(&[0xFFFF, 0xFFFE], DataType::Int32, false), Some(-2.0));
+        assert_eq!(decode_registers(&[0x3FC0, 0x0000], DataType::Float32, false), Some(1.5));
+    }
+
+    #[test]
+    fn little_word_order_is_low_word_first() {
+        assert_eq!(decode_registers(&[0x0001, 0x0000], DataType::Uint32, true), Some(1.0));
+        assert_eq!(decode_registers(&[0xFFFE, 0xFFFF], DataType::Int32, true), Some(-2.0));
+        assert_eq!(decode_registers(&[0x0000, 0x3FC0], DataType::Float32, true), Some(1.5));
+    }
+
+    #[test]
+    fn rejects_short_reads_and_nan() {
+        assert_eq!(decode_registers(&[0x3FC0], DataType::Float32, false), None);
+        assert_eq!(decode_registers(&[0x7FC0, 0x0000], DataType::Float32, false), None);
+        assert_eq!(decode_registers(&[0x7F80, 0x0000], DataType::Float32, false), None);
+    }
+
+    #[test]
+    fn register_counts() {
+        assert_eq!(DataType::parse("int16").unwrap().register_count(), 1);
+        assert_eq!(DataType::parse("float32").unwrap().register_count(), 2);
+        assert!(DataType::parse("float64").is_err());
+    }
+}
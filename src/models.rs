@@ -3,13 +3,18 @@ use bigdecimal::*;
 use chrono::NaiveDateTime;
 use diesel::prelude::*;
 use diesel::pg::PgConnection;
+use diesel::r2d2::{ConnectionManager, Pool};
 use diesel::dsl::{sum,avg};
 use chrono::prelude::*;
 use dotenv::dotenv;
 use std::env;
+use std::collections::{BTreeMap,HashMap};
+use serde_derive::Serialize;
 
 use super::error::Error;
-use super::schema::{devices,rainfalls,water_depths,calculations};
+use super::rating::Rating;
+use super::runoff::{self, RunoffModel, RunoffState};
+use super::schema::{devices,rainfalls,water_depths,calculations,sensor_points,rain_counters,raw_messages,dead_letters,device_status,qc_rules,device_health,rating_curves,rating_points,model_parameters,model_state,model_checkpoints};
 
 #[derive(Debug, Queryable,Identifiable)]
 #[table_name="devices"]
@@ -25,7 +30,11 @@ pub struct Device {
     pub two_hour_design:BigDecimal,
     pub three_design:BigDecimal,
     pub stream_width:Option<BigDecimal>,
-    pub rainfall_area:Option<BigDecimal>
+    pub rainfall_area:Option<BigDecimal>,
+    pub sl651_address:Option<String>,
+    pub offline_after:Option<i32>,
+    pub primary_channel:Option<String>,
+    pub broker:Option<String>,
 }
 
 impl Device {
@@ -47,6 +56,12 @@ impl Device {
     pub fn three_hour_def(&self) -> f32 {
         decimal_to_f32(&self.three_design)
     }
+    pub fn is_primary_channel(&self, channel:&str) -> bool {
+        match self.primary_channel.as_ref() {
+            Some(p) => p == channel,
+            None => true,
+        }
+    }
 }
 
 #[derive(Queryable, Identifiable, Associations)]
@@ -61,6 +76,10 @@ pub struct Rainfall {
     pub one_half_hour:i64,
     pub two_hour:i64,
     pub three_hour:i64,    
+    pub counter:Option<BigDecimal>,
+    pub counter_event:String,
+    pub receive_time:NaiveDateTime,
+    pub quality:i16,
 }
 
 #[derive(Insertable)]
@@ -74,10 +93,14 @@ struct NewRainfall {
     one_half_hour:i64,
     two_hour:i64,
     three_hour:i64,     
+    counter:Option<BigDecimal>,
+    counter_event:String,
+    receive_time:NaiveDateTime,
+    quality:i16,
 }
 impl NewRainfall {
-    pub fn new(device_id:i32,value:f32) -> NewRainfall {
-        let (now_stamp,half_stamp,one_stamp,one_half_stamp,two_stamp,three_stamp) = six_timestamps();
+    pub fn new(device_id:i32,value:f32,counter:Option<f32>,event:CounterEvent,time:ReadingTime,quality:i16) -> NewRainfall {
+        let (now_stamp,half_stamp,one_stamp,one_half_stamp,two_stamp,three_stamp) = six_timestamps(time.observed);
         let naive_time = NaiveDateTime::from_timestamp(now_stamp, 0);
         NewRainfall {
             device_id,
@@ -88,8 +111,137 @@ impl NewRainfall {
             one_half_hour:one_half_stamp,
             two_hour:two_stamp,
             three_hour:three_stamp,
+            counter:counter.map(BigDecimal::from),
+            counter_event:event.as_str().to_string(),
+            receive_time:time.received_time(),
+            quality,
+        }
+    }
+}
+
+// quality code of readings that passed QC, anything else is suspect
+pub const QUALITY_GOOD:i16 = 0;
+
+// observed: device-reported time of the reading, received: when the server got it
+#[derive(Debug, Clone, Copy)]
+pub struct ReadingTime {
+    pub observed:i64,
+    pub received:i64,
+}
+
+// device clocks running further ahead than this are ignored
+const MAX_CLOCK_AHEAD:i64 = 600;
+
+impl ReadingTime {
+    // device times older than max_age_secs before receipt are a reset clock, not a backlog
+    pub fn new(observed:Option<i64>,received:i64,max_age_secs:i64) -> ReadingTime {
+        let observed = match observed {
+            Some(o) if o <= received + MAX_CLOCK_AHEAD && o >= received - max_age_secs => o,
+            _ => received,
+        };
+        ReadingTime {
+            observed,
+            received,
+        }
+    }
+    pub fn observed_time(&self) -> NaiveDateTime {
+        NaiveDateTime::from_timestamp(self.observed, 0)
+    }
+    pub fn received_time(&self) -> NaiveDateTime {
+        NaiveDateTime::from_timestamp(self.received, 0)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CounterEvent {
+    Normal,
+    Baseline,
+    Reset,
+    Rollover,
+    Increment,
+    // a drop within the jitter band, the baseline stays where it was
+    Jitter,
+    // observed before the baseline, which has already moved past it
+    Late,
+}
+
+impl CounterEvent {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Normal => "normal",
+            Self::Baseline => "baseline",
+            Self::Reset => "reset",
+            Self::Rollover => "rollover",
+            Self::Increment => "increment",
+            Self::Jitter => "jitter",
+            Self::Late => "late",
         }
     }
+
+    // jitter and late readings don't move the baseline
+    pub fn moves_baseline(&self) -> bool {
+        !matches!(self, Self::Jitter | Self::Late)
+    }
+}
+
+#[derive(Debug, Queryable)]
+pub struct DeviceStatus {
+    pub device_id:i32,
+    pub online:bool,
+    pub last_seen:NaiveDateTime,
+    pub last_payload_size:Option<i32>,
+    pub last_values:String,
+    pub offline_reason:Option<String>,
+    pub status_time:NaiveDateTime,
+}
+
+#[derive(Insertable)]
+#[table_name="device_status"]
+struct NewDeviceStatus<'a> {
+    device_id:i32,
+    online:bool,
+    last_seen:NaiveDateTime,
+    last_payload_size:Option<i32>,
+    last_values:&'a str,
+    status_time:NaiveDateTime,
+}
+
+#[derive(Debug, Queryable)]
+pub struct DeviceHealth {
+    pub id:i64,
+    pub device_id:i32,
+    pub metric:String,
+    pub value:BigDecimal,
+    pub alarm:bool,
+    pub create_time:NaiveDateTime,
+    pub half_hour:i64,
+    pub receive_time:NaiveDateTime,
+}
+
+impl DeviceHealth {
+    pub fn value_def(&self) -> f32 {
+        decimal_to_f32(&self.value)
+    }
+}
+
+#[derive(Insertable)]
+#[table_name="device_health"]
+struct NewDeviceHealth<'a> {
+    device_id:i32,
+    metric:&'a str,
+    value:BigDecimal,
+    alarm:bool,
+    create_time:NaiveDateTime,
+    half_hour:i64,
+    receive_time:NaiveDateTime,
+}
+
+#[derive(Insertable)]
+#[table_name="rain_counters"]
+struct NewRainCounter {
+    device_id:i32,
+    counter:BigDecimal,
+    update_time:NaiveDateTime,
 }
 
 #[derive(Insertable)]
@@ -97,29 +249,35 @@ impl NewRainfall {
 struct NewWaterDepth {
     device_id:i32,
     value:BigDecimal,
-    flow_value:BigDecimal,    
+    flow_value:Option<BigDecimal>,    
     create_time:NaiveDateTime,
     half_hour:i64,
     one_hour:i64,
     one_half_hour:i64,
     two_hour:i64,
     three_hour:i64,    
+    receive_time:NaiveDateTime,
+    quality:i16,
+    channel:String,
 }
 
 impl NewWaterDepth {
-    fn new(device_id:i32,value:f32,flow_value:f32) -> NewWaterDepth {
-        let (now_stamp,half_stamp,one_stamp,one_half_stamp,two_stamp,three_stamp) = six_timestamps();
+    fn new(device_id:i32,channel:&str,value:f32,flow_value:Option<f32>,time:ReadingTime,quality:i16) -> NewWaterDepth {
+        let (now_stamp,half_stamp,one_stamp,one_half_stamp,two_stamp,three_stamp) = six_timestamps(time.observed);
         let naive_time = NaiveDateTime::from_timestamp(now_stamp, 0);        
         NewWaterDepth {
             device_id,
             value:BigDecimal::from(value),
-            flow_value:BigDecimal::from(flow_value),
+            flow_value:flow_value.map(BigDecimal::from),
             create_time:naive_time,
             half_hour:half_stamp,
             one_hour:one_stamp,
             one_half_hour:one_half_stamp,
             two_hour:two_stamp,
             three_hour:three_stamp,
+            receive_time:time.received_time(),
+            quality,
+            channel:channel.to_string(),
         }
     }
 }
@@ -137,6 +295,9 @@ struct NewCalculation {
     one_half_hour:i64,
     two_hour:i64,
     three_hour:i64,    
+    receive_time:NaiveDateTime,
+    quality:i16,
+    model:String,
 }
 
 #[derive(Queryable, Identifiable, Associations)]
@@ -153,16 +314,247 @@ pub struct Calculation {
     one_half_hour:i64,
     two_hour:i64,
     three_hour:i64,       
+    receive_time:NaiveDateTime,
+    quality:i16,
+    model:String,
+}
+
+#[derive(Debug, Queryable, Identifiable)]
+#[table_name="sensor_points"]
+pub struct SensorPoint {
+    pub id:i32,
+    pub device_id:Option<i32>,
+    pub point_id:String,
+    pub slave_addr:Option<String>,
+    pub slave_index:Option<String>,
+    pub kind:String,
+    pub scale:BigDecimal,
+    pub value_offset:BigDecimal,
+    pub channel:Option<String>,
+}
+
+impl SensorPoint {
+    pub fn scale_def(&self) -> f32 {
+        self.scale.to_f32().unwrap_or(1.0)
+    }
+    pub fn offset_def(&self) -> f32 {
+        self.value_offset.to_f32().unwrap_or(0.0)
+    }
+}
+
+#[derive(Debug, Queryable, Identifiable)]
+#[table_name="qc_rules"]
+pub struct QcRule {
+    pub id:i32,
+    pub device_id:Option<i32>,
+    pub kind:String,
+    pub min_value:Option<BigDecimal>,
+    pub max_value:Option<BigDecimal>,
+    pub max_rate:Option<BigDecimal>,
+    pub flatline_secs:Option<i32>,
+}
+
+#[derive(Debug, Queryable, Identifiable)]
+#[table_name="rating_curves"]
+pub struct RatingCurveRow {
+    pub id:i32,
+    pub device_id:i32,
+    pub curve_type:String,
+    pub coefficient:Option<BigDecimal>,
+    pub exponent:Option<BigDecimal>,
+    pub width:Option<BigDecimal>,
+    pub side_slope:Option<BigDecimal>,
+    pub notch_angle:Option<BigDecimal>,
+    pub stage_offset:BigDecimal,
+    pub min_stage:Option<BigDecimal>,
+    pub max_stage:Option<BigDecimal>,
+}
+
+#[derive(Debug, Queryable, Identifiable)]
+#[table_name="rating_points"]
+pub struct RatingPoint {
+    pub id:i32,
+    pub curve_id:i32,
+    pub stage:BigDecimal,
+    pub discharge:BigDecimal,
+}
+
+#[derive(Debug, Queryable, Identifiable)]
+#[primary_key(device_id)]
+#[table_name="model_parameters"]
+pub struct ModelParameter {
+    pub device_id:i32,
+    pub catchment_area:Option<BigDecimal>,
+    pub storage_capacity:BigDecimal,
+    pub loss_rate:BigDecimal,
+    pub reservoir_a:BigDecimal,
+    pub reservoir_b:BigDecimal,
+    pub update_time:NaiveDateTime,
+    pub runoff_model:String,
+    pub curve_number:BigDecimal,
+    pub runoff_coefficient:BigDecimal,
+}
+
+#[derive(Insertable, AsChangeset)]
+#[table_name="model_parameters"]
+#[changeset_options(treat_none_as_null="true")]
+struct NewModelParameter {
+    device_id:i32,
+    catchment_area:Option<BigDecimal>,
+    storage_capacity:BigDecimal,
+    loss_rate:BigDecimal,
+    reservoir_a:BigDecimal,
+    reservoir_b:BigDecimal,
+    update_time:NaiveDateTime,
+    runoff_model:String,
+    curve_number:BigDecimal,
+    runoff_coefficient:BigDecimal,
+}
+
+// catchment area in km2, storage deficit floor in mm, loss in mm per day,
+// a and b of the nonlinear reservoir q = a*w^2 + b*w; curve_number is used by
+// the scs model, runoff_coefficient by the rational model
+#[derive(Debug, Clone)]
+pub struct RunoffParams {
+    pub runoff_model:String,
+    pub catchment_area:f32,
+    pub storage_capacity:f32,
+    pub loss_rate:f32,
+    pub reservoir_a:f32,
+    pub reservoir_b:f32,
+    pub curve_number:f32,
+    pub runoff_coefficient:f32,
+}
+
+impl Default for RunoffParams {
+    fn default() -> RunoffParams {
+        RunoffParams {
+            runoff_model:"reservoir".to_string(),
+            catchment_area:55.0,
+            storage_capacity:30.0,
+            loss_rate:3.0,
+            reservoir_a:0.00045,
+            reservoir_b:0.7,
+            curve_number:75.0,
+            runoff_coefficient:0.5,
+        }
+    }
+}
+
+#[derive(Debug, Queryable, Identifiable)]
+#[primary_key(device_id)]
+#[table_name="model_state"]
+pub struct ModelState {
+    pub device_id:i32,
+    pub storage:BigDecimal,
+    pub wi:BigDecimal,
+    pub state_time:NaiveDateTime,
+    pub calculation_id:Option<i32>,
+    pub update_time:NaiveDateTime,
+    pub model:String,
+}
+
+impl ModelState {
+    pub fn storage_def(&self) -> f32 {
+        decimal_to_f32(&self.storage)
+    }
+    pub fn wi_def(&self) -> f32 {
+        decimal_to_f32(&self.wi)
+    }
+    fn runoff_state(&self) -> RunoffState {
+        RunoffState {
+            storage:self.storage_def(),
+            wi:self.wi_def(),
+        }
+    }
+}
+
+#[derive(Insertable, AsChangeset)]
+#[table_name="model_state"]
+#[changeset_options(treat_none_as_null="true")]
+struct NewModelState<'a> {
+    device_id:i32,
+    storage:&'a BigDecimal,
+    wi:&'a BigDecimal,
+    state_time:NaiveDateTime,
+    calculation_id:Option<i32>,
+    update_time:NaiveDateTime,
+    model:&'a str,
+}
+
+#[derive(Debug, Queryable, Identifiable)]
+#[table_name="model_checkpoints"]
+pub struct ModelCheckpoint {
+    pub id:i64,
+    pub device_id:i32,
+    pub storage:BigDecimal,
+    pub wi:BigDecimal,
+    pub state_time:NaiveDateTime,
+    pub calculation_id:Option<i32>,
+    pub reason:String,
+    pub restored_from:Option<i64>,
+    pub create_time:NaiveDateTime,
+    pub model:String,
+}
+
+impl ModelCheckpoint {
+    pub fn storage_def(&self) -> f32 {
+        decimal_to_f32(&self.storage)
+    }
+    pub fn wi_def(&self) -> f32 {
+        decimal_to_f32(&self.wi)
+    }
+}
+
+#[derive(Insertable)]
+#[table_name="model_checkpoints"]
+struct NewModelCheckpoint<'a> {
+    device_id:i32,
+    storage:&'a BigDecimal,
+    wi:&'a BigDecimal,
+    state_time:NaiveDateTime,
+    calculation_id:Option<i32>,
+    reason:&'a str,
+    restored_from:Option<i64>,
+    create_time:NaiveDateTime,
+    model:&'a str,
+}
+
+#[derive(Insertable)]
+#[table_name="raw_messages"]
+struct NewRawMessage<'a> {
+    topic:&'a str,
+    payload:&'a str,
+    receive_time:NaiveDateTime,
+}
+
+#[derive(Debug, Queryable, Identifiable)]
+#[table_name="dead_letters"]
+pub struct DeadLetter {
+    pub id:i32,
+    pub topic:String,
+    pub payload:String,
+    pub reason:String,
+    pub receive_time:NaiveDateTime,
+}
+
+#[derive(Insertable)]
+#[table_name="dead_letters"]
+struct NewDeadLetter<'a> {
+    topic:&'a str,
+    payload:&'a str,
+    reason:&'a str,
+    receive_time:NaiveDateTime,
 }
 
 impl NewCalculation {
-    fn new(device_id:i32,storage:f32,wi:f32,quantity:f32) -> NewCalculation {
-        let (now_stamp,half_stamp,one_stamp,one_half_stamp,two_stamp,three_stamp) = six_timestamps();
+    fn new(device_id:i32,state:RunoffState,quantity:f32,time:ReadingTime,quality:i16,model:&str) -> NewCalculation {
+        let (now_stamp,half_stamp,one_stamp,one_half_stamp,two_stamp,three_stamp) = six_timestamps(time.observed);
         let naive_time = NaiveDateTime::from_timestamp(now_stamp, 0);        
         NewCalculation {
             device_id,
-            storage:BigDecimal::from(storage),
-            wi:BigDecimal::from(wi),
+            storage:BigDecimal::from(state.storage),
+            wi:BigDecimal::from(state.wi),
             quantity:BigDecimal::from(quantity),
             create_time:naive_time,
             half_hour:half_stamp,
@@ -170,18 +562,33 @@ impl NewCalculation {
             one_half_hour:one_half_stamp,
             two_hour:two_stamp,
             three_hour:three_stamp,
+            receive_time:time.received_time(),
+            quality,
+            model:model.to_string(),
         }
     }
 }
 
-pub fn db_connection() -> Result<PgConnection, Error> {
+pub type PgPool = Pool<ConnectionManager<PgConnection>>;
+
+fn database_url() -> String {
     dotenv().ok();
 
-    let database_url = env::var("DATABASE_URL").expect("DATABASE_URL must be set");
-    PgConnection::establish(&database_url)
+    env::var("DATABASE_URL").expect("DATABASE_URL must be set")
+}
+
+pub fn db_connection() -> Result<PgConnection, Error> {
+    PgConnection::establish(&database_url())
         .map_err(|a| Error::DatabaseError(format!("Error connecting to {}", a.to_string())))
 }
 
+pub fn db_pool(size:u32) -> Result<PgPool, Error> {
+    Pool::builder()
+        .max_size(size)
+        .build(ConnectionManager::<PgConnection>::new(database_url()))
+        .map_err(|a| Error::DatabaseError(format!("Error creating pool to {}", a.to_string())))
+}
+
 pub fn get_device(conn: &PgConnection, d_id: i32) -> Result<Device, Error> {
     use super::schema::devices::dsl::*;
     devices
@@ -199,109 +606,667 @@ pub fn get_id_by_deviceid(conn: &PgConnection, d_id: String) -> Result<i32,Error
         .map_err(|e| Error::DatabaseError(format!("Error get device to {}", e.to_string())))
 }
 
-pub fn topics() -> Result<Vec<String>,Error> {
+pub fn get_id_by_sl651_address(conn: &PgConnection, address: &str) -> Result<i32,Error> {
+    use super::schema::devices::dsl::*;
+    devices
+        .select(id)
+        .filter(sl651_address.eq(address))
+        .first::<i32>(conn)
+        .map_err(|e| Error::DatabaseError(format!("Error get device to {}", e.to_string())))
+}
+
+// device ids served by a broker, the first broker also takes devices without one
+pub fn broker_devices(conn:&PgConnection,broker_name:&str,default_broker:bool) -> Result<Vec<String>,Error> {
     use super::schema::devices::dsl::*;
-    let conn = db_connection()?;
-    devices.select(device_id)
-        .load::<String>(&conn)
-        .map(|result|result.iter().map(|topic|format!("$USR/DevJsonTx/{}",topic)).collect())
+    devices.select((device_id, broker))
+        .load::<(String,Option<String>)>(conn)
+        .map(|result|result.iter()
+            .filter(|(_, b)| match b {
+                Some(b) => b == broker_name,
+                None => default_broker,
+            })
+            .map(|(key, _)|key.clone()).collect())
         .map_err(|e|Error::DatabaseError(e.to_string()))
         
 }
 
+// device ids and broker names of devices assigned to none of the given brokers
+pub fn unassigned_devices(conn:&PgConnection,broker_names:&[&str]) -> Result<Vec<(String,String)>,Error> {
+    use super::schema::devices::dsl::*;
+    devices.select((device_id, broker))
+        .order_by(device_id)
+        .load::<(String,Option<String>)>(conn)
+        .map(|result| result.into_iter()
+            .filter_map(|(key, b)| b.map(|b| (key, b)))
+            .filter(|(_, b)| !broker_names.contains(&b.as_str()))
+            .collect())
+        .map_err(|e| Error::DatabaseError(format!("Error get device brokers to {}", e.to_string())))
+}
+
+pub fn sensor_points(conn:&PgConnection) -> Result<Vec<SensorPoint>,Error> {
+    use super::schema::sensor_points::dsl::*;
+    sensor_points
+        .order_by(id)
+        .load::<SensorPoint>(conn)
+        .map_err(|e| Error::DatabaseError(format!("Error get sensor points to {}", e.to_string())))
+}
+
+pub fn new_raw_message(conn:&PgConnection,r_topic:&str,r_payload:&str,received:i64) -> Result<usize,Error> {
+    use super::schema::raw_messages::dsl::*;
+    let raw_message = NewRawMessage {
+        topic:r_topic,
+        payload:r_payload,
+        receive_time:NaiveDateTime::from_timestamp(received, 0),
+    };
+    diesel::insert_into(raw_messages)
+        .values(&raw_message)
+        .execute(conn)
+        .map_err(|e| Error::DatabaseError(format!("Error create raw message to {}", e.to_string())))
+}
+
+pub fn purge_raw_messages(conn:&PgConnection,retention_days:i64) -> Result<usize,Error> {
+    use super::schema::raw_messages::dsl::*;
+    let before = NaiveDateTime::from_timestamp(Utc::now().timestamp()-retention_days*24*3600, 0);
+    diesel::delete(raw_messages.filter(receive_time.lt(before)))
+        .execute(conn)
+        .map_err(|e| Error::DatabaseError(format!("Error purge raw messages to {}", e.to_string())))
+}
+
+// the latest checkpoint of each device is kept, so there is always one to restore
+pub fn purge_checkpoints(conn:&PgConnection,retention_days:i64) -> Result<usize,Error> {
+    use diesel::sql_types::Timestamptz;
+    let before = NaiveDateTime::from_timestamp(Utc::now().timestamp()-retention_days*24*3600, 0);
+    diesel::sql_query("DELETE FROM model_checkpoints WHERE create_time < $1 \
+            AND id NOT IN (SELECT MAX(id) FROM model_checkpoints GROUP BY device_id)")
+        .bind::<Timestamptz,_>(before)
+        .execute(conn)
+        .map_err(|e| Error::DatabaseError(format!("Error purge model checkpoints to {}", e.to_string())))
+}
+
+// true when the fingerprint is new, false for a duplicate; with a window the fingerprint
+// is only a duplicate if it was last seen less than window_secs ago
+pub fn claim_fingerprint(conn:&PgConnection,dev_id:i32,fp:&str,received:i64,window_secs:Option<i64>) -> Result<bool,Error> {
+    use diesel::sql_types::{Int4,Varchar,Timestamptz,Int8};
+    let seen = NaiveDateTime::from_timestamp(received, 0);
+    let query = match window_secs {
+        Some(_) => "INSERT INTO message_fingerprints (device_id, fingerprint, receive_time) VALUES ($1, $2, $3) \
+            ON CONFLICT (device_id, fingerprint) DO UPDATE SET receive_time = EXCLUDED.receive_time \
+            WHERE message_fingerprints.receive_time < EXCLUDED.receive_time - make_interval(secs => $4::float8)",
+        None => "INSERT INTO message_fingerprints (device_id, fingerprint, receive_time) VALUES ($1, $2, $3) \
+            ON CONFLICT (device_id, fingerprint) DO NOTHING",
+    };
+    let query = diesel::sql_query(query)
+        .bind::<Int4,_>(dev_id)
+        .bind::<Varchar,_>(fp)
+        .bind::<Timestamptz,_>(seen);
+    let count = match window_secs {
+        Some(w) => query.bind::<Int8,_>(w).execute(conn),
+        None => query.execute(conn),
+    };
+    count.map(|c| c > 0)
+        .map_err(|e| Error::DatabaseError(format!("Error saving fingerprint to {}", e.to_string())))
+}
+
+// only the payload hash fingerprints ("...#md5"), they just guard a short window; a device
+// timestamp fingerprint ("...@time") is kept, a retransmission of that reading can come any time
+pub fn purge_fingerprints(conn:&PgConnection,retention_days:i64) -> Result<usize,Error> {
+    use diesel::sql_types::Timestamptz;
+    let before = NaiveDateTime::from_timestamp(Utc::now().timestamp()-retention_days*24*3600, 0);
+    diesel::sql_query("DELETE FROM message_fingerprints WHERE receive_time < $1 \
+            AND fingerprint ~ '#[0-9a-f]{32}$'")
+        .bind::<Timestamptz,_>(before)
+        .execute(conn)
+        .map_err(|e| Error::DatabaseError(format!("Error purge fingerprints to {}", e.to_string())))
+}
+
+// a message arrived: the device is online again and its last values are replaced
+pub fn touch_device_status(conn:&PgConnection,dev_id:i32,payload_size:Option<i32>,values:&str,received:i64) -> Result<usize,Error> {
+    use super::schema::device_status::dsl::*;
+    let seen = NaiveDateTime::from_timestamp(received, 0);
+    let status = NewDeviceStatus {
+        device_id:dev_id,
+        online:true,
+        last_seen:seen,
+        last_payload_size:payload_size,
+        last_values:values,
+        status_time:seen,
+    };
+    // status_time is when the device went on- or offline, so it only moves when coming back
+    diesel::update(device_status.filter(device_id.eq(dev_id)).filter(online.eq(false)))
+        .set(status_time.eq(seen))
+        .execute(conn)
+        .map_err(|e| Error::DatabaseError(format!("Error save device status to {}", e.to_string())))?;
+    diesel::insert_into(device_status)
+        .values(&status)
+        .on_conflict(device_id)
+        .do_update()
+        .set((
+            online.eq(true),
+            last_seen.eq(seen),
+            last_payload_size.eq(payload_size),
+            last_values.eq(values),
+            offline_reason.eq(None::<String>),
+        ))
+        .execute(conn)
+        .map_err(|e| Error::DatabaseError(format!("Error save device status to {}", e.to_string())))
+}
+
+pub fn mark_device_offline(conn:&PgConnection,dev_id:i32,reason:&str) -> Result<usize,Error> {
+    use super::schema::device_status::dsl::*;
+    diesel::update(device_status.filter(device_id.eq(dev_id)).filter(online.eq(true)))
+        .set((
+            online.eq(false),
+            offline_reason.eq(reason),
+            status_time.eq(Utc::now().naive_utc()),
+        ))
+        .execute(conn)
+        .map_err(|e| Error::DatabaseError(format!("Error save device status to {}", e.to_string())))
+}
+
+// devices silent for longer than their offline_after (or the default) go offline
+pub fn mark_silent_devices_offline(conn:&PgConnection,default_secs:i64) -> Result<usize,Error> {
+    use diesel::sql_types::Int8;
+    diesel::sql_query("UPDATE device_status SET online = FALSE, offline_reason = 'timeout', status_time = now() \
+        FROM devices WHERE devices.id = device_status.device_id AND device_status.online \
+        AND device_status.last_seen < now() - make_interval(secs => COALESCE(devices.offline_after, $1)::float8)")
+        .bind::<Int8,_>(default_secs)
+        .execute(conn)
+        .map_err(|e| Error::DatabaseError(format!("Error update device status to {}", e.to_string())))
+}
+
+// every device with its status, None for devices that never reported
+pub fn device_statuses(conn:&PgConnection) -> Result<Vec<(Device,Option<DeviceStatus>)>,Error> {
+    devices::table
+        .left_join(device_status::table)
+        .order_by(devices::id)
+        .load::<(Device,Option<DeviceStatus>)>(conn)
+        .map_err(|e| Error::DatabaseError(format!("Error get device status to {}", e.to_string())))
+}
+
+pub fn new_dead_letter(conn:&PgConnection,d_topic:&str,d_payload:&str,d_reason:&str,received:i64) -> Result<usize,Error> {
+    use super::schema::dead_letters::dsl::*;
+    let dead_letter = NewDeadLetter {
+        topic:d_topic,
+        payload:d_payload,
+        reason:d_reason,
+        receive_time:NaiveDateTime::from_timestamp(received, 0),
+    };
+    diesel::insert_into(dead_letters)
+        .values(&dead_letter)
+        .execute(conn)
+        .map_err(|e| Error::DatabaseError(format!("Error create dead letter to {}", e.to_string())))
+}
+
+pub fn recent_dead_letters(conn:&PgConnection,limit:i64) -> Result<Vec<DeadLetter>,Error> {
+    use super::schema::dead_letters::dsl::*;
+    dead_letters
+        .order_by(id.desc())
+        .limit(limit)
+        .load::<DeadLetter>(conn)
+        .map_err(|e| Error::DatabaseError(format!("Error get dead letters to {}", e.to_string())))
+}
+
 pub fn new_rainfall(
+    conn:&PgConnection,
     device:i32,
     data:f32,
-    
+    counter_value:Option<f32>,
+    event:CounterEvent,
+    time:ReadingTime,
+    data_quality:i16,
 ) -> Result<usize,Error> {
     use super::schema::rainfalls::dsl::*;
-    let conn = db_connection()?;
-    
-    let rainfall = NewRainfall::new(device, data);
+    let rainfall = NewRainfall::new(device, data, counter_value, event, time, data_quality);
     diesel::insert_into(rainfalls)
     .values(&rainfall)
-    .execute(&conn)
+    .execute(conn)
     .map_err(|a| {
         Error::DatabaseError(format!("Error create rainfalls to {}", a.to_string()))
     })
 }
 
-pub fn new_water_depth(device:i32,data:f32) -> Result<usize,Error> {
+pub fn qc_rules(conn:&PgConnection) -> Result<Vec<QcRule>,Error> {
+    use super::schema::qc_rules::dsl::*;
+    qc_rules
+        .order_by(id)
+        .load::<QcRule>(conn)
+        .map_err(|e| Error::DatabaseError(format!("Error get qc rules to {}", e.to_string())))
+}
+
+// latest good rainfall increment before the given time
+pub fn last_good_rainfall(conn:&PgConnection,dev_id:i32,before:NaiveDateTime) -> Result<Option<(f32,NaiveDateTime)>,Error> {
+    use super::schema::rainfalls::dsl::*;
+    rainfalls
+        .select((value, create_time))
+        .filter(device_id.eq(dev_id).and(quality.eq(QUALITY_GOOD)).and(create_time.lt(before)))
+        .order_by(create_time.desc())
+        .first::<(BigDecimal,NaiveDateTime)>(conn)
+        .optional()
+        .map(|r| r.map(|(v, t)| (decimal_to_f32(&v), t)))
+        .map_err(|e| Error::DatabaseError(format!("Error get rainfall to {}", e.to_string())))
+}
+
+pub fn last_good_depth(conn:&PgConnection,dev_id:i32,depth_channel:&str,before:NaiveDateTime) -> Result<Option<(f32,NaiveDateTime)>,Error> {
+    use super::schema::water_depths::dsl::*;
+    water_depths
+        .select((value, create_time))
+        .filter(device_id.eq(dev_id).and(channel.eq(depth_channel)))
+        .filter(quality.eq(QUALITY_GOOD).and(create_time.lt(before)))
+        .order_by(create_time.desc())
+        .first::<(BigDecimal,NaiveDateTime)>(conn)
+        .optional()
+        .map(|r| r.map(|(v, t)| (decimal_to_f32(&v), t)))
+        .map_err(|e| Error::DatabaseError(format!("Error get water depth to {}", e.to_string())))
+}
+
+// since when the device has been reporting exactly this depth, None if the last reading differs
+pub fn depth_unchanged_since(conn:&PgConnection,dev_id:i32,depth_channel:&str,depth:f32) -> Result<Option<NaiveDateTime>,Error> {
+    use super::schema::water_depths::dsl::*;
+    use diesel::dsl::{max,min};
+    let depth = format!("{:.2}", depth).parse::<BigDecimal>().unwrap_or_default();
+    let last_change = water_depths
+        .select(max(create_time))
+        .filter(device_id.eq(dev_id).and(channel.eq(depth_channel)).and(value.ne(&depth)))
+        .first::<Option<NaiveDateTime>>(conn)
+        .map_err(|e| Error::DatabaseError(format!("Error get water depth to {}", e.to_string())))?;
+    let last_change = last_change.unwrap_or_else(|| NaiveDateTime::from_timestamp(0, 0));
+    water_depths
+        .select(min(create_time))
+        .filter(device_id.eq(dev_id).and(channel.eq(depth_channel)).and(value.eq(&depth)).and(create_time.gt(last_change)))
+        .first::<Option<NaiveDateTime>>(conn)
+        .map_err(|e| Error::DatabaseError(format!("Error get water depth to {}", e.to_string())))
+}
+
+// (min, max) for the metric, a device row replaces the default one
+pub fn health_threshold(conn:&PgConnection,dev_id:i32,h_metric:&str) -> Result<Option<(Option<f32>,Option<f32>)>,Error> {
+    use super::schema::health_thresholds::dsl::*;
+    let rows = health_thresholds
+        .select((device_id, min_value, max_value))
+        .filter(metric.eq(h_metric).and(device_id.eq(dev_id).or(device_id.is_null())))
+        .load::<(Option<i32>,Option<BigDecimal>,Option<BigDecimal>)>(conn)
+        .map_err(|e| Error::DatabaseError(format!("Error get health thresholds to {}", e.to_string())))?;
+    Ok(rows.into_iter()
+        .max_by_key(|(d, _, _)| d.is_some())
+        .map(|(_, min, max)| (min.map(|v| decimal_to_f32(&v)), max.map(|v| decimal_to_f32(&v)))))
+}
+
+pub fn new_device_health(conn:&PgConnection,dev_id:i32,h_metric:&str,data:f32,h_alarm:bool,time:ReadingTime) -> Result<usize,Error> {
+    use super::schema::device_health::dsl::*;
+    let health = NewDeviceHealth {
+        device_id:dev_id,
+        metric:h_metric,
+        value:BigDecimal::from(data),
+        alarm:h_alarm,
+        create_time:time.observed_time(),
+        half_hour:time.observed/1800,
+        receive_time:time.received_time(),
+    };
+    diesel::insert_into(device_health)
+        .values(&health)
+        .execute(conn)
+        .map_err(|e| Error::DatabaseError(format!("Error create device health to {}", e.to_string())))
+}
+
+// newest reading of every device and metric
+pub fn latest_device_health(conn:&PgConnection) -> Result<Vec<DeviceHealth>,Error> {
+    use super::schema::device_health::dsl::*;
+    device_health
+        .distinct_on((device_id, metric))
+        .order_by((device_id, metric, create_time.desc()))
+        .load::<DeviceHealth>(conn)
+        .map_err(|e| Error::DatabaseError(format!("Error get device health to {}", e.to_string())))
+}
+
+// the baseline counter and when it was observed; locked until the transaction ends, so MQTT
+// and HTTP readings of one device can't both take their increment from the same baseline
+pub fn rain_counter(conn:&PgConnection,dev_id:i32) -> Result<Option<(f32,i64)>,Error> {
+    use super::schema::rain_counters::dsl::*;
+    rain_counters
+        .find(dev_id)
+        .select((counter, update_time))
+        .for_update()
+        .first::<(BigDecimal,NaiveDateTime)>(conn)
+        .optional()
+        .map(|c| c.map(|(c, t)| (decimal_to_f32(&c), t.timestamp())))
+        .map_err(|e| Error::DatabaseError(format!("Error get rain counter to {}", e.to_string())))
+}
+
+// the baseline counter of the latest rainfall at or before the given time, for replays
+pub fn rain_counter_before(conn:&PgConnection,dev_id:i32,before:NaiveDateTime) -> Result<Option<(f32,i64)>,Error> {
+    use super::schema::rainfalls::dsl::*;
+    rainfalls
+        .select((counter, create_time))
+        .filter(device_id.eq(dev_id).and(counter.is_not_null()).and(create_time.le(before)))
+        .filter(counter_event.ne_all(vec![CounterEvent::Jitter.as_str(), CounterEvent::Late.as_str()]))
+        .order_by((create_time.desc(), id.desc()))
+        .first::<(Option<BigDecimal>,NaiveDateTime)>(conn)
+        .optional()
+        .map(|c| c.and_then(|(c, t)| c.map(|c| (decimal_to_f32(&c), t.timestamp()))))
+        .map_err(|e| Error::DatabaseError(format!("Error get rain counter to {}", e.to_string())))
+}
+
+// the first live counter reading after a replayed range was taken against the baseline from
+// before the gap, so it holds the gap's rain too; its increment is taken again from the last
+// replayed counter, returns its time
+pub fn rebase_next_rainfall(conn:&PgConnection,dev_id:i32,after:i64,counter_max:Option<f32>) -> Result<Option<i64>,Error> {
+    use super::schema::rainfalls::dsl::*;
+    let after_time = NaiveDateTime::from_timestamp(after, 0);
+    let next = rainfalls
+        .select((id, counter, create_time))
+        .filter(device_id.eq(dev_id).and(counter.is_not_null()).and(create_time.gt(after_time)))
+        .filter(counter_event.ne_all(vec![CounterEvent::Jitter.as_str(), CounterEvent::Late.as_str()]))
+        .order_by((create_time, id))
+        .first::<(i32,Option<BigDecimal>,NaiveDateTime)>(conn)
+        .optional()
+        .map_err(|e| Error::DatabaseError(format!("Error get rainfall to {}", e.to_string())))?;
+    let (next_id, next_counter, next_time) = match next {
+        Some((i, Some(c), t)) => (i, decimal_to_f32(&c), t),
+        _ => return Ok(None),
+    };
+    let last = rain_counter_before(conn, dev_id, after_time)?;
+    let (rainfall_value, event) = rain_increment(last, next_counter, next_time.timestamp(), counter_max);
+    diesel::update(rainfalls.find(next_id))
+        .set((value.eq(BigDecimal::from(rainfall_value)), counter_event.eq(event.as_str())))
+        .execute(conn)
+        .map_err(|e| Error::DatabaseError(format!("Error update rainfall to {}", e.to_string())))?;
+    Ok(Some(next_time.timestamp()))
+}
+
+pub fn save_rain_counter(conn:&PgConnection,dev_id:i32,value:f32,time:ReadingTime) -> Result<usize,Error> {
+    use super::schema::rain_counters::dsl::*;
+    let rain_counter = NewRainCounter {
+        device_id:dev_id,
+        counter:BigDecimal::from(value),
+        update_time:time.observed_time(),
+    };
+    diesel::insert_into(rain_counters)
+        .values(&rain_counter)
+        .on_conflict(device_id)
+        .do_update()
+        .set((counter.eq(&rain_counter.counter), update_time.eq(&rain_counter.update_time)))
+        .execute(conn)
+        .map_err(|e| Error::DatabaseError(format!("Error save rain counter to {}", e.to_string())))
+}
+
+// drops smaller than this are sensor jitter, not a counter reset
+const COUNTER_EPSILON:f32 = 0.05;
+// a drop counts as rollover when the previous reading was within this fraction of counter_max
+const ROLLOVER_BAND:f32 = 0.1;
+
+// rainfall increment since the baseline counter observed at last_time, and how the counter moved
+pub fn rain_increment(last:Option<(f32,i64)>,counter:f32,observed:i64,counter_max:Option<f32>) -> (f32,CounterEvent) {
+    let (last, last_time) = match last {
+        Some(l) => l,
+        None => return (0.0, CounterEvent::Baseline),
+    };
+    if observed < last_time {
+        return (0.0, CounterEvent::Late);
+    }
+    if counter >= last {
+        return (counter - last, CounterEvent::Normal);
+    }
+    if counter >= last - COUNTER_EPSILON {
+        return (0.0, CounterEvent::Jitter);
+    }
+    match counter_max {
+        Some(max) if last >= max*(1.0-ROLLOVER_BAND) => ((max - last).max(0.0) + counter, CounterEvent::Rollover),
+        _ => (counter, CounterEvent::Reset),
+    }
+}
+
+// flow is only derived from the device's primary channel
+pub fn new_water_depth(conn:&PgConnection,device:i32,depth_channel:&str,data:f32,time:ReadingTime,data_quality:i16) -> Result<usize,Error> {
     use super::schema::water_depths::dsl::*;
-    let conn = db_connection()?;
-    let f_value = cal_flow_value(&conn, device, data)?;
-    let water_depth = NewWaterDepth::new(device, data,f_value);
+    let dev = get_device(conn, device)?;
+    let f_value = if dev.is_primary_channel(depth_channel) {
+        cal_flow_value(conn, &dev, data)
+    } else {
+        None
+    };
+    let water_depth = NewWaterDepth::new(device, depth_channel, data,f_value,time,data_quality);
     diesel::insert_into(water_depths)
     .values(&water_depth)
-    .execute(&conn)
+    .execute(conn)
     .map_err(|a| {
         Error::DatabaseError(format!("Error create water_depth to {}", a.to_string()))
     })
 }
 
-fn cal_flow_value(conn:&PgConnection,device:i32,data:f32) -> Result<f32,Error> {
-    let device = get_device(conn, device)?;
-    let m = 1.5;
-    let b = device.stream_width.map(|w|decimal_to_f32(&w)).unwrap_or(0.0);
-    
-    let f_value = m*b*data.powf(1.5);
-    Ok(f_value)
+// the device's rating curve, or the old rectangular weir over stream_width when it has none
+fn cal_flow_value(conn:&PgConnection,device:&Device,data:f32) -> Option<f32> {
+    let curve = match rating_curve(conn, device.id) {
+        Ok(Some((row, points))) => Rating::new(&row, &points),
+        Ok(None) => Ok(Rating::rectangular_weir(device.stream_width.as_ref().map(|w|decimal_to_f32(&w)).unwrap_or(0.0))),
+        Err(e) => Err(e),
+    };
+    match curve {
+        Ok(c) => c.flow(data),
+        Err(e) => {
+            println!("Rating curve of device {}: {}", device.device_id, e.to_string());
+            None
+        }
+    }
 }
 
-pub fn new_calculation(dev_id:i32,rain:f32) -> Result<usize,Error> {
-    use super::schema::calculations::dsl::*;
-    let conn = db_connection()?;
-    let mut si_1 = -30.0;let mut wi_1 = 0.0;let mut interval=300.0;
-    let recent_cal = recent_calculation(&conn, dev_id);
-    if let Ok(rc) = recent_cal {
-        si_1 = decimal_to_f32(&rc.storage);
-        wi_1 = decimal_to_f32(&rc.wi);
-        let now_stamps = Utc::now().timestamp();
-        let recent_stamps = rc.create_time.timestamp();
-        interval = (now_stamps - recent_stamps).to_f32().unwrap_or(300.0);    
-        
-    }
-    let (i_j,s_i) = cal_storage(si_1, rain, interval);
-    let (qu,w_i) = cal_quantity(i_j, wi_1, interval);
-    let calculation = NewCalculation::new(dev_id, s_i, w_i, qu);
-    
-    diesel::insert_into(calculations)
-    .values(&calculation)
-    .execute(&conn)
-    .map_err(|a| {
-        Error::DatabaseError(format!("Error create calculation to {}", a.to_string()))
+pub fn rating_curve(conn:&PgConnection,dev_id:i32) -> Result<Option<(RatingCurveRow,Vec<RatingPoint>)>,Error> {
+    let row = rating_curves::table
+        .filter(rating_curves::device_id.eq(dev_id))
+        .first::<RatingCurveRow>(conn)
+        .optional()
+        .map_err(|e| Error::DatabaseError(format!("Error get rating curve to {}", e.to_string())))?;
+    let row = match row {
+        Some(r) => r,
+        None => return Ok(None),
+    };
+    let points = rating_points::table
+        .filter(rating_points::curve_id.eq(row.id))
+        .order_by(rating_points::stage)
+        .load::<RatingPoint>(conn)
+        .map_err(|e| Error::DatabaseError(format!("Error get rating points to {}", e.to_string())))?;
+    Ok(Some((row, points)))
+}
+
+// suspect rain is stored with its quality code but feeds nothing into the runoff model
+// the model continues from model_state, every step is checkpointed with its calculation
+// returns 0 when the reading is older than model_state and nothing was stepped
+pub fn new_calculation(conn:&PgConnection,dev_id:i32,rain:f32,time:ReadingTime,rain_quality:i16) -> Result<usize,Error> {
+    let (params, _) = runoff_params(conn, dev_id)?;
+    let model = runoff::runoff_model(&params)?;
+    conn.transaction::<_,Error,_>(|| {
+        let state = lock_model_state(conn, dev_id)?;
+        // the model never steps back, an older reading waits for a recompute of its range
+        if let Some(st) = state.as_ref().filter(|st| time.observed < st.state_time.timestamp()) {
+            println!("Reading of device {} at {} is older than the model state at {}, recompute to include it",
+                dev_id, time.observed, st.state_time.timestamp());
+            return Ok(0);
+        }
+        // a state left by another model starts the device over
+        let previous = state
+            .filter(|st| st.model == model.name())
+            .map(|st| (st.runoff_state(), st.state_time.timestamp()));
+        let rain = if rain_quality == QUALITY_GOOD { rain } else { 0.0 };
+        let (state,qu) = cal_runoff(model.as_ref(), previous, rain, time.observed);
+        let calculation = NewCalculation::new(dev_id, state, qu, time, rain_quality, model.name());
+        let calc_id = diesel::insert_into(calculations::table)
+            .values(&calculation)
+            .returning(calculations::id)
+            .get_result::<i32>(conn)
+            .map_err(|a| {
+                Error::DatabaseError(format!("Error create calculation to {}", a.to_string()))
+            })?;
+        save_model_state(conn, &NewModelCheckpoint {
+            device_id:dev_id,
+            storage:&calculation.storage,
+            wi:&calculation.wi,
+            state_time:calculation.create_time,
+            calculation_id:Some(calc_id),
+            reason:"calculation",
+            restored_from:None,
+            create_time:Utc::now().naive_utc(),
+            model:&calculation.model,
+        })?;
+        Ok(1)
     })
-    
 }
-// I净，s_i
-fn cal_storage(si_1:f32,rain:f32,interval:f32) -> (f32,f32){
-    let si = si_1+rain - 3.0*interval/24.0/12.0/300.0;
-    let si = si.max(-30.0);
-    if si > 0.0 {
-        (si,0.0)
-    } else {
-        (0.0,si)
+
+pub fn model_state(conn:&PgConnection,dev_id:i32) -> Result<Option<ModelState>,Error> {
+    model_state::table
+        .find(dev_id)
+        .first::<ModelState>(conn)
+        .optional()
+        .map_err(|e| Error::DatabaseError(format!("Error get model state to {}", e.to_string())))
+}
+
+// locked until the transaction ends, so two steps of a device can't start from the same state
+fn lock_model_state(conn:&PgConnection,dev_id:i32) -> Result<Option<ModelState>,Error> {
+    model_state::table
+        .find(dev_id)
+        .for_update()
+        .first::<ModelState>(conn)
+        .optional()
+        .map_err(|e| Error::DatabaseError(format!("Error get model state to {}", e.to_string())))
+}
+
+// writes the state and the checkpoint of it, returns the checkpoint id
+fn save_model_state(conn:&PgConnection,checkpoint:&NewModelCheckpoint) -> Result<i64,Error> {
+    let state = NewModelState {
+        device_id:checkpoint.device_id,
+        storage:checkpoint.storage,
+        wi:checkpoint.wi,
+        state_time:checkpoint.state_time,
+        calculation_id:checkpoint.calculation_id,
+        update_time:checkpoint.create_time,
+        model:checkpoint.model,
+    };
+    diesel::insert_into(model_state::table)
+        .values(&state)
+        .on_conflict(model_state::device_id)
+        .do_update()
+        .set(&state)
+        .execute(conn)
+        .map_err(|e| Error::DatabaseError(format!("Error save model state to {}", e.to_string())))?;
+    diesel::insert_into(model_checkpoints::table)
+        .values(checkpoint)
+        .returning(model_checkpoints::id)
+        .get_result::<i64>(conn)
+        .map_err(|e| Error::DatabaseError(format!("Error save model checkpoint to {}", e.to_string())))
+}
+
+pub fn model_checkpoints(conn:&PgConnection,dev_id:i32,limit:i64) -> Result<Vec<ModelCheckpoint>,Error> {
+    model_checkpoints::table
+        .filter(model_checkpoints::device_id.eq(dev_id))
+        .order_by(model_checkpoints::id.desc())
+        .limit(limit)
+        .load::<ModelCheckpoint>(conn)
+        .map_err(|e| Error::DatabaseError(format!("Error get model checkpoints to {}", e.to_string())))
+}
+
+// the device continues from the checkpoint's state; the restore is itself checkpointed
+pub fn restore_model_state(conn:&PgConnection,dev_id:i32,checkpoint_id:i64) -> Result<i64,Error> {
+    conn.transaction::<_,Error,_>(|| {
+        let checkpoint = model_checkpoints::table
+            .filter(model_checkpoints::id.eq(checkpoint_id))
+            .filter(model_checkpoints::device_id.eq(dev_id))
+            .first::<ModelCheckpoint>(conn)
+            .optional()?
+            .ok_or_else(|| Error::PayloadError(format!("no checkpoint {} of device {}", checkpoint_id, dev_id)))?;
+        lock_model_state(conn, dev_id)?;
+        save_model_state(conn, &NewModelCheckpoint {
+            device_id:dev_id,
+            storage:&checkpoint.storage,
+            wi:&checkpoint.wi,
+            state_time:checkpoint.state_time,
+            calculation_id:checkpoint.calculation_id,
+            reason:"restore",
+            restored_from:Some(checkpoint.id),
+            create_time:Utc::now().naive_utc(),
+            model:&checkpoint.model,
+        })
+    })
+}
+
+// previous is the state and time of the calculation before; (state, quantity)
+fn cal_runoff(model:&dyn RunoffModel,previous:Option<(RunoffState,i64)>,rain:f32,observed:i64) -> (RunoffState,f32) {
+    let mut state = model.init_state();let mut interval=300.0;
+    if let Some((state_1, recent_stamps)) = previous {
+        state = state_1;
+        if observed > recent_stamps {
+            interval = (observed - recent_stamps).to_f32().unwrap_or(300.0);    
+        }
     }
+    model.step(state, rain, interval)
 }
 
-// quantity,wi
-fn cal_quantity(i_j:f32,wi_1:f32,interval:f32) -> (f32,f32) {
-    let f= 55.0;let a = 0.00045;let b = 0.7;
-    let iil = i_j*f*3.333;
-    let d = b/(2.0*a);
-    let g = (b*b+4.0*a*iil).powf(0.5)/(2.0*a);    
-    let k = (wi_1 +d - g)/(wi_1+d+g)*(-2.0*g*interval/10000.0).exp();
-    let wi = (1.0+k)/(1.0-k)*g -d;
-    let qi = a*wi*wi+b*wi;
-    (qi,wi)
+// the stored parameters of the device, or the defaults; true when a row exists
+pub fn runoff_params(conn:&PgConnection,dev_id:i32) -> Result<(RunoffParams,bool),Error> {
+    let device = get_device(conn, dev_id)?;
+    let stored = model_parameters::table
+        .find(dev_id)
+        .first::<ModelParameter>(conn)
+        .optional()
+        .map_err(|e| Error::DatabaseError(format!("Error get model parameters to {}", e.to_string())))?;
+    let to_f32 = |v:&BigDecimal| v.to_f32().unwrap_or(0.0);
+    let device_area = device.rainfall_area.as_ref().map(to_f32).filter(|a| *a > 0.0);
+    let defaults = RunoffParams::default();
+    let params = match stored.as_ref() {
+        Some(p) => RunoffParams {
+            runoff_model:p.runoff_model.clone(),
+            catchment_area:p.catchment_area.as_ref().map(to_f32).or(device_area).unwrap_or(defaults.catchment_area),
+            storage_capacity:to_f32(&p.storage_capacity),
+            loss_rate:to_f32(&p.loss_rate),
+            reservoir_a:to_f32(&p.reservoir_a),
+            reservoir_b:to_f32(&p.reservoir_b),
+            curve_number:to_f32(&p.curve_number),
+            runoff_coefficient:to_f32(&p.runoff_coefficient),
+        },
+        None => RunoffParams {
+            catchment_area:device_area.unwrap_or(defaults.catchment_area),
+            ..defaults
+        },
+    };
+    Ok((params, stored.is_some()))
+}
+
+// false while the device uses devices.rainfall_area
+pub fn catchment_area_stored(conn:&PgConnection,dev_id:i32) -> Result<bool,Error> {
+    model_parameters::table
+        .find(dev_id)
+        .select(model_parameters::catchment_area)
+        .first::<Option<BigDecimal>>(conn)
+        .optional()
+        .map(|a| a.map(|a| a.is_some()).unwrap_or(false))
+        .map_err(|e| Error::DatabaseError(format!("Error get model parameters to {}", e.to_string())))
+}
+
+// device_area: store no catchment area and keep using devices.rainfall_area
+pub fn save_runoff_params(conn:&PgConnection,dev_id:i32,params:&RunoffParams,device_area:bool) -> Result<usize,Error> {
+    let row = NewModelParameter {
+        device_id:dev_id,
+        catchment_area:if device_area { None } else { Some(BigDecimal::from(params.catchment_area)) },
+        storage_capacity:BigDecimal::from(params.storage_capacity),
+        loss_rate:BigDecimal::from(params.loss_rate),
+        reservoir_a:BigDecimal::from(params.reservoir_a),
+        reservoir_b:BigDecimal::from(params.reservoir_b),
+        update_time:Utc::now().naive_utc(),
+        runoff_model:params.runoff_model.clone(),
+        curve_number:BigDecimal::from(params.curve_number),
+        runoff_coefficient:BigDecimal::from(params.runoff_coefficient),
+    };
+    diesel::insert_into(model_parameters::table)
+        .values(&row)
+        .on_conflict(model_parameters::device_id)
+        .do_update()
+        .set(&row)
+        .execute(conn)
+        .map_err(|e| Error::DatabaseError(format!("Error save model parameters to {}", e.to_string())))
 }
 
 pub fn recent_calculation(conn:&PgConnection,dev_id:i32) -> Result<Calculation,Error> {
     use super::schema::*;
 
     calculations::table
-        .find(dev_id)
-        .order_by(calculations::create_time)
+        .filter(calculations::device_id.eq(dev_id))
+        .order_by((calculations::create_time.desc(), calculations::id.desc()))
         .first::<Calculation>(conn)
         .map_err(|a| {
             Error::DatabaseError(format!("Error get calculation to {}", a.to_string()))
@@ -309,8 +1274,167 @@ pub fn recent_calculation(conn:&PgConnection,dev_id:i32) -> Result<Calculation,E
 
 
 }
-fn six_timestamps() -> (i64,i64,i64,i64,i64,i64) {
-    let now_stamps = Utc::now().timestamp();
+#[derive(Serialize, Debug)]
+pub struct CalculationChange {
+    pub time:String,
+    pub before:Option<f32>,
+    pub after:Option<f32>,
+}
+
+#[derive(Serialize, Debug)]
+pub struct RecomputeReport {
+    pub device_id:i32,
+    pub dry_run:bool,
+    pub rainfalls:usize,
+    pub deleted:usize,
+    pub inserted:usize,
+    pub total_before:f32,
+    pub total_after:f32,
+    pub peak_before:f32,
+    pub peak_after:f32,
+    // calculations after the range keep the state they were computed with
+    pub later_rows:i64,
+    pub changes:Vec<CalculationChange>,
+}
+
+// regenerates the calculations of [from, to] from the stored rainfalls, starting from the
+// state of the last calculation before the range; a dry run only reports the difference
+pub fn recompute_calculations(conn:&PgConnection,dev_id:i32,from:i64,to:i64,dry_run:bool) -> Result<RecomputeReport,Error> {
+    if from > to {
+        return Err(Error::PayloadError(format!("recompute range from {} is after to {}", from, to)));
+    }
+    let (params, _) = runoff_params(conn, dev_id)?;
+    let model = runoff::runoff_model(&params)?;
+    let from_time = NaiveDateTime::from_timestamp(from, 0);
+    let to_time = NaiveDateTime::from_timestamp(to, 0);
+    conn.transaction::<_,Error,_>(|| {
+        // held from the first read, a live step can't land in or after the range meanwhile
+        if !dry_run {
+            lock_model_state(conn, dev_id)?;
+        }
+        let before = calculations::table
+            .filter(calculations::device_id.eq(dev_id))
+            .filter(calculations::create_time.lt(from_time))
+            .order_by((calculations::create_time.desc(), calculations::id.desc()))
+            .first::<Calculation>(conn)
+            .optional()?;
+        let old = calculations::table
+            .filter(calculations::device_id.eq(dev_id))
+            .filter(calculations::create_time.between(from_time, to_time))
+            .order_by((calculations::create_time, calculations::id))
+            .load::<Calculation>(conn)?;
+        let rains = rainfalls::table
+            .filter(rainfalls::device_id.eq(dev_id))
+            .filter(rainfalls::create_time.between(from_time, to_time))
+            .order_by((rainfalls::create_time, rainfalls::id))
+            .load::<Rainfall>(conn)?;
+        let later_rows = calculations::table
+            .filter(calculations::device_id.eq(dev_id))
+            .filter(calculations::create_time.gt(to_time))
+            .count()
+            .get_result::<i64>(conn)?;
+
+        let mut previous = before
+            .filter(|rc| rc.model == model.name())
+            .map(|rc| (RunoffState { storage:decimal_to_f32(&rc.storage), wi:decimal_to_f32(&rc.wi) }, rc.create_time.timestamp()));
+        let mut rows = vec![];
+        for r in &rains {
+            let time = ReadingTime {
+                observed:r.create_time.timestamp(),
+                received:r.receive_time.timestamp(),
+            };
+            let rain = if r.quality == QUALITY_GOOD { decimal_to_f32(&r.value) } else { 0.0 };
+            let (state,qu) = cal_runoff(model.as_ref(), previous, rain, time.observed);
+            let row = NewCalculation::new(dev_id, state, qu, time, r.quality, model.name());
+            // carried forward as stored, the way new_calculation reads it back
+            previous = Some((RunoffState { storage:decimal_to_f32(&row.storage), wi:decimal_to_f32(&row.wi) }, time.observed));
+            rows.push(row);
+        }
+
+        // several rows at one time are paired in order, keyed by (time, n-th row at that time)
+        let nth = |seen:&mut HashMap<i64,usize>,t:i64| {
+            let n = seen.entry(t).or_insert(0);
+            *n += 1;
+            (t, *n - 1)
+        };
+        let mut by_time:BTreeMap<(i64,usize),(Option<f32>,Option<f32>)> = BTreeMap::new();
+        let mut seen = HashMap::new();
+        for c in &old {
+            let key = nth(&mut seen, c.create_time.timestamp());
+            by_time.entry(key).or_insert((None, None)).0 = Some(decimal_to_f32(&c.quantity));
+        }
+        seen.clear();
+        for c in &rows {
+            let key = nth(&mut seen, c.create_time.timestamp());
+            by_time.entry(key).or_insert((None, None)).1 = Some(decimal_to_f32(&c.quantity));
+        }
+        let changes = by_time.into_iter()
+            .filter(|(_, (b, a))| match (b, a) {
+                (Some(b), Some(a)) => (b - a).abs() >= 0.01,
+                _ => true,
+            })
+            .map(|((t, _), (b, a))| CalculationChange {
+                time:NaiveDateTime::from_timestamp(t+8*3600, 0).to_string(),
+                before:b,
+                after:a,
+            })
+            .collect();
+        let quantities_before:Vec<f32> = old.iter().map(|c| decimal_to_f32(&c.quantity)).collect();
+        let quantities_after:Vec<f32> = rows.iter().map(|c| decimal_to_f32(&c.quantity)).collect();
+
+        let mut report = RecomputeReport {
+            device_id:dev_id,
+            dry_run,
+            rainfalls:rains.len(),
+            deleted:0,
+            inserted:0,
+            total_before:quantities_before.iter().sum(),
+            total_after:quantities_after.iter().sum(),
+            peak_before:quantities_before.iter().cloned().fold(0.0, f32::max),
+            peak_after:quantities_after.iter().cloned().fold(0.0, f32::max),
+            later_rows,
+            changes,
+        };
+        if !dry_run {
+            report.deleted = diesel::delete(calculations::table
+                    .filter(calculations::device_id.eq(dev_id))
+                    .filter(calculations::create_time.between(from_time, to_time)))
+                .execute(conn)?;
+            report.inserted = diesel::insert_into(calculations::table)
+                .values(&rows)
+                .execute(conn)?;
+            // nothing after the range, so the model continues from the recomputed end
+            if later_rows == 0 {
+                let latest = calculations::table
+                    .filter(calculations::device_id.eq(dev_id))
+                    .order_by((calculations::create_time.desc(), calculations::id.desc()))
+                    .first::<Calculation>(conn)
+                    .optional()?;
+                match latest {
+                    Some(rc) => {
+                        save_model_state(conn, &NewModelCheckpoint {
+                            device_id:dev_id,
+                            storage:&rc.storage,
+                            wi:&rc.wi,
+                            state_time:rc.create_time,
+                            calculation_id:Some(rc.id),
+                            reason:"recompute",
+                            restored_from:None,
+                            create_time:Utc::now().naive_utc(),
+                            model:&rc.model,
+                        })?;
+                    }
+                    None => {
+                        diesel::delete(model_state::table.find(dev_id)).execute(conn)?;
+                    }
+                }
+            }
+        }
+        Ok(report)
+    })
+}
+
+fn six_timestamps(now_stamps:i64) -> (i64,i64,i64,i64,i64,i64) {
     let half_stamps = now_stamps/1800;
     let one_stamps = now_stamps/3600;
     let one_half_stamps = now_stamps/5400;
@@ -362,6 +1486,12 @@ pub fn device_ids(conn:&PgConnection) -> Result<Vec<i32>,Error> {
         })  
 }
 
+// one value per device of dev_ids, in their order; None for a device without good readings
+fn by_device(dev_ids:&[i32],ids:&[i32],values:&[Option<BigDecimal>]) -> Vec<Option<f32>> {
+    let values:HashMap<i32,&Option<BigDecimal>> = ids.iter().cloned().zip(values.iter()).collect();
+    dev_ids.iter().map(|id| values.get(id).and_then(|v| v.as_ref().map(decimal_to_f32))).collect()
+}
+
 pub fn rainfall_by_half(conn:&PgConnection,dev_ids:&Vec<i32>) -> Result<Vec<Option<f32>>,Error> {
     use super::schema::*;
     let now_stamps = Utc::now().timestamp();
@@ -373,15 +1503,25 @@ pub fn rainfall_by_half(conn:&PgConnection,dev_ids:&Vec<i32>) -> Result<Vec<Opti
         .select(sum(rainfalls::value))
         .group_by(rainfalls::device_id)
         .order_by(rainfalls::device_id)        
+        .filter(rainfalls::quality.eq(QUALITY_GOOD))
         .filter(rainfalls::create_time.ge(n_time).and(rainfalls::device_id.eq_any(dev_ids)))        
         .load::<Option<BigDecimal>>(conn)
         .map_err(|a| {
         Error::DatabaseError(format!("Error get rainfalls sum value by half hours to {}", a.to_string()))
     })?;    
 
-    let data:Vec<_> = value_sum.iter().map(|v|v.as_ref().map(|v1|decimal_to_f32(&v1))).collect();
-    
-    Ok(data)
+    let ids = rainfalls::table                
+        .select(rainfalls::device_id)
+        .group_by(rainfalls::device_id)
+        .order_by(rainfalls::device_id)        
+        .filter(rainfalls::quality.eq(QUALITY_GOOD))
+        .filter(rainfalls::create_time.ge(n_time).and(rainfalls::device_id.eq_any(dev_ids)))        
+        .load::<i32>(conn)
+        .map_err(|a| {
+        Error::DatabaseError(format!("Error get rainfalls sum value by half hours to {}", a.to_string()))
+    })?;    
+
+    Ok(by_device(dev_ids, &ids, &value_sum))
 }
 
 pub fn flow_by_half(conn:&PgConnection,dev_ids:&Vec<i32>) -> Result<Vec<Option<f32>>,Error> {
@@ -394,6 +1534,7 @@ pub fn flow_by_half(conn:&PgConnection,dev_ids:&Vec<i32>) -> Result<Vec<Option<f
     let flow_avg = water_depths::table
         .group_by(water_depths::device_id)
         .order_by(water_depths::device_id)        
+        .filter(water_depths::quality.eq(QUALITY_GOOD))
         .filter(water_depths::create_time.ge(n_time).and(water_depths::device_id.eq_any(dev_ids)))
         .select(avg(water_depths::flow_value))        
         .load::<Option<BigDecimal>>(conn)
@@ -401,9 +1542,18 @@ pub fn flow_by_half(conn:&PgConnection,dev_ids:&Vec<i32>) -> Result<Vec<Option<f
         Error::DatabaseError(format!("Error get rainfalls sum value by half hours to {}", a.to_string()))
     })?;    
 
-    let data:Vec<_> = flow_avg.iter().map(|v|v.as_ref().map(|v1|decimal_to_f32(&v1))).collect();
-    
-    Ok(data)
+    let ids = water_depths::table
+        .group_by(water_depths::device_id)
+        .order_by(water_depths::device_id)        
+        .filter(water_depths::quality.eq(QUALITY_GOOD))
+        .filter(water_depths::create_time.ge(n_time).and(water_depths::device_id.eq_any(dev_ids)))
+        .select(water_depths::device_id)        
+        .load::<i32>(conn)
+        .map_err(|a| {
+        Error::DatabaseError(format!("Error get rainfalls sum value by half hours to {}", a.to_string()))
+    })?;    
+
+    Ok(by_device(dev_ids, &ids, &flow_avg))
 }
 
 pub fn a_flows_by_half(conn:&PgConnection,dev_id:i32) -> Result<(Vec<Option<f32>>,Vec<String>),Error> {
@@ -417,6 +1567,7 @@ pub fn a_flows_by_half(conn:&PgConnection,dev_id:i32) -> Result<(Vec<Option<f32>
         .select(avg(water_depths::flow_value))
         .group_by(water_depths::half_hour)
         .order_by(water_depths::half_hour)        
+        .filter(water_depths::quality.eq(QUALITY_GOOD))
         .filter(water_depths::create_time.ge(n_time).and(water_depths::device_id.eq(dev_id)))        
         .load::<Option<BigDecimal>>(conn)
         .map_err(|a| {
@@ -427,6 +1578,7 @@ pub fn a_flows_by_half(conn:&PgConnection,dev_id:i32) -> Result<(Vec<Option<f32>
         .select(water_depths::half_hour)
         .group_by(water_depths::half_hour)
         .order_by(water_depths::half_hour)        
+        .filter(water_depths::quality.eq(QUALITY_GOOD))
         .filter(water_depths::create_time.ge(n_time).and(water_depths::device_id.eq(dev_id)))        
         .load::<Option<i64>>(conn)
         .map_err(|a| {
@@ -447,8 +1599,11 @@ pub fn depth_by_half(conn:&PgConnection,dev_ids:&Vec<i32>) -> Result<Vec<Option<
     
         
     let depth_avg = water_depths::table
+        .inner_join(devices::table)
         .group_by(water_depths::device_id)
         .order_by(water_depths::device_id)        
+        .filter(water_depths::quality.eq(QUALITY_GOOD))
+        .filter(water_depths::channel.nullable().eq(devices::primary_channel).or(devices::primary_channel.is_null()))
         .filter(water_depths::create_time.ge(n_time).and(water_depths::device_id.eq_any(dev_ids)))
         .select(avg(water_depths::value))        
         .load::<Option<BigDecimal>>(conn)
@@ -456,23 +1611,38 @@ pub fn depth_by_half(conn:&PgConnection,dev_ids:&Vec<i32>) -> Result<Vec<Option<
         Error::DatabaseError(format!("Error get rainfalls sum value by half hours to {}", a.to_string()))
     })?;    
 
-    let data:Vec<_> = depth_avg.iter().map(|v|v.as_ref().map(|v1|decimal_to_f32(&v1))).collect();
-    
-    Ok(data)
+    let ids = water_depths::table
+        .inner_join(devices::table)
+        .group_by(water_depths::device_id)
+        .order_by(water_depths::device_id)        
+        .filter(water_depths::quality.eq(QUALITY_GOOD))
+        .filter(water_depths::channel.nullable().eq(devices::primary_channel).or(devices::primary_channel.is_null()))
+        .filter(water_depths::create_time.ge(n_time).and(water_depths::device_id.eq_any(dev_ids)))
+        .select(water_depths::device_id)        
+        .load::<i32>(conn)
+        .map_err(|a| {
+        Error::DatabaseError(format!("Error get rainfalls sum value by half hours to {}", a.to_string()))
+    })?;    
+
+    Ok(by_device(dev_ids, &ids, &depth_avg))
 }
 
-pub fn a_depths_by_half(conn:&PgConnection,dev_id:i32) -> Result<(Vec<Option<f32>>,Vec<String>),Error> {
+// channel None: the primary channel, or every channel when the device has none
+pub fn a_depths_by_half(conn:&PgConnection,dev_id:i32,depth_channel:Option<&str>) -> Result<(Vec<Option<f32>>,Vec<String>),Error> {
     use super::schema::*;
     let now_stamps = Utc::now().timestamp();
     let half_stamps = now_stamps-8*3600;
     let n_time = NaiveDateTime::from_timestamp(half_stamps, 0);    
+    let channels = chart_channels(conn, dev_id, depth_channel)?;
     
         
     let depth_avg = water_depths::table                
         .select(avg(water_depths::value))
         .group_by(water_depths::half_hour)
         .order_by(water_depths::half_hour)        
-        .filter(water_depths::create_time.ge(n_time).and(water_depths::device_id.eq(dev_id)))        
+        .filter(water_depths::quality.eq(QUALITY_GOOD))
+        .filter(water_depths::create_time.ge(n_time).and(water_depths::device_id.eq(dev_id)))
+        .filter(water_depths::channel.eq_any(&channels))        
         .load::<Option<BigDecimal>>(conn)
         .map_err(|a| {
         Error::DatabaseError(format!("Error get rainfalls sum value by half hours to {}", a.to_string()))
@@ -482,7 +1652,9 @@ pub fn a_depths_by_half(conn:&PgConnection,dev_id:i32) -> Result<(Vec<Option<f32
         .select(water_depths::half_hour)
         .group_by(water_depths::half_hour)
         .order_by(water_depths::half_hour)        
-        .filter(water_depths::create_time.ge(n_time).and(water_depths::device_id.eq(dev_id)))        
+        .filter(water_depths::quality.eq(QUALITY_GOOD))
+        .filter(water_depths::create_time.ge(n_time).and(water_depths::device_id.eq(dev_id)))
+        .filter(water_depths::channel.eq_any(&channels))        
         .load::<Option<i64>>(conn)
         .map_err(|a| {
         Error::DatabaseError(format!("Error get rainfalls sum value by half hours to {}", a.to_string()))
@@ -494,6 +1666,41 @@ pub fn a_depths_by_half(conn:&PgConnection,dev_id:i32) -> Result<(Vec<Option<f32
     Ok((depth_avg,times))
 }
 
+pub fn a_health_by_half(conn:&PgConnection,dev_id:i32,h_metric:&str) -> Result<(Vec<Option<f32>>,Vec<String>),Error> {
+    use super::schema::*;
+    let now_stamps = Utc::now().timestamp();
+    let half_stamps = now_stamps-24*3600;
+    let n_time = NaiveDateTime::from_timestamp(half_stamps, 0);    
+    
+        
+    let health_avg = device_health::table                
+        .select(avg(device_health::value))
+        .group_by(device_health::half_hour)
+        .order_by(device_health::half_hour)        
+        .filter(device_health::create_time.ge(n_time).and(device_health::device_id.eq(dev_id)))        
+        .filter(device_health::metric.eq(h_metric))
+        .load::<Option<BigDecimal>>(conn)
+        .map_err(|a| {
+        Error::DatabaseError(format!("Error get device health by half hours to {}", a.to_string()))
+    })?; 
+    
+    let times = device_health::table                
+        .select(device_health::half_hour)
+        .group_by(device_health::half_hour)
+        .order_by(device_health::half_hour)        
+        .filter(device_health::create_time.ge(n_time).and(device_health::device_id.eq(dev_id)))        
+        .filter(device_health::metric.eq(h_metric))
+        .load::<i64>(conn)
+        .map_err(|a| {
+        Error::DatabaseError(format!("Error get device health by half hours to {}", a.to_string()))
+    })?;
+
+    let times:Vec<_> = times.iter().map(|t|NaiveDateTime::from_timestamp(t*1800+8*3600, 0).time().to_string()).collect();
+    let health_avg:Vec<_> = health_avg.iter().map(|v|v.as_ref().map(|v1|decimal_to_f32(&v1))).collect();
+    
+    Ok((health_avg,times))
+}
+
 pub fn quantity_by_half(conn:&PgConnection,dev_ids:&Vec<i32>) -> Result<Vec<Option<f32>>,Error> {
     use super::schema::*;
     let now_stamps = Utc::now().timestamp();
@@ -504,6 +1711,7 @@ pub fn quantity_by_half(conn:&PgConnection,dev_ids:&Vec<i32>) -> Result<Vec<Opti
     let quantity_avg = calculations::table
         .group_by(calculations::device_id)
         .order_by(calculations::device_id)        
+        .filter(calculations::quality.eq(QUALITY_GOOD))
         .filter(calculations::create_time.ge(n_time).and(calculations::device_id.eq_any(dev_ids)))
         .select(avg(calculations::quantity))        
         .load::<Option<BigDecimal>>(conn)
@@ -511,9 +1719,18 @@ pub fn quantity_by_half(conn:&PgConnection,dev_ids:&Vec<i32>) -> Result<Vec<Opti
         Error::DatabaseError(format!("Error get rainfalls sum value by half hours to {}", a.to_string()))
     })?;    
 
-    let data:Vec<_> = quantity_avg.iter().map(|v|v.as_ref().map(|v1|decimal_to_f32(&v1))).collect();
-    
-    Ok(data)
+    let ids = calculations::table
+        .group_by(calculations::device_id)
+        .order_by(calculations::device_id)        
+        .filter(calculations::quality.eq(QUALITY_GOOD))
+        .filter(calculations::create_time.ge(n_time).and(calculations::device_id.eq_any(dev_ids)))
+        .select(calculations::device_id)        
+        .load::<i32>(conn)
+        .map_err(|a| {
+        Error::DatabaseError(format!("Error get rainfalls sum value by half hours to {}", a.to_string()))
+    })?;    
+
+    Ok(by_device(dev_ids, &ids, &quantity_avg))
 }
 
 pub fn a_quantitys_by_half(conn:&PgConnection,dev_id:i32) -> Result<(Vec<Option<f32>>,Vec<String>),Error> {
@@ -527,6 +1744,7 @@ pub fn a_quantitys_by_half(conn:&PgConnection,dev_id:i32) -> Result<(Vec<Option<
         .select(avg(calculations::quantity))
         .group_by(calculations::half_hour)
         .order_by(calculations::half_hour)        
+        .filter(calculations::quality.eq(QUALITY_GOOD))
         .filter(calculations::create_time.ge(n_time).and(calculations::device_id.eq(dev_id)))        
         .load::<Option<BigDecimal>>(conn)
         .map_err(|a| {
@@ -537,6 +1755,7 @@ pub fn a_quantitys_by_half(conn:&PgConnection,dev_id:i32) -> Result<(Vec<Option<
         .select(calculations::half_hour)
         .group_by(calculations::half_hour)
         .order_by(calculations::half_hour)        
+        .filter(calculations::quality.eq(QUALITY_GOOD))
         .filter(calculations::create_time.ge(n_time).and(calculations::device_id.eq(dev_id)))        
         .load::<i64>(conn)
         .map_err(|a| {
@@ -560,6 +1779,7 @@ pub fn a_rainfalls_by_half(conn:&PgConnection,dev_id:i32) -> Result<(Vec<Option<
         .select(sum(rainfalls::value))
         .group_by(rainfalls::half_hour)
         .order_by(rainfalls::half_hour)        
+        .filter(rainfalls::quality.eq(QUALITY_GOOD))
         .filter(rainfalls::create_time.ge(n_time).and(rainfalls::device_id.eq(dev_id)))        
         .load::<Option<BigDecimal>>(conn)
         .map_err(|a| {
@@ -570,6 +1790,7 @@ pub fn a_rainfalls_by_half(conn:&PgConnection,dev_id:i32) -> Result<(Vec<Option<
         .select(rainfalls::half_hour)
         .group_by(rainfalls::half_hour)
         .order_by(rainfalls::half_hour)        
+        .filter(rainfalls::quality.eq(QUALITY_GOOD))
         .filter(rainfalls::create_time.ge(n_time).and(rainfalls::device_id.eq(dev_id)))        
         .load::<i64>(conn)
         .map_err(|a| {
@@ -593,15 +1814,25 @@ pub fn rainfall_by_one(conn:&PgConnection,dev_ids:&Vec<i32>) -> Result<Vec<Optio
         .select(sum(rainfalls::value))
         .group_by(rainfalls::device_id)
         .order_by(rainfalls::device_id)        
+        .filter(rainfalls::quality.eq(QUALITY_GOOD))
         .filter(rainfalls::create_time.ge(n_time).and(rainfalls::device_id.eq_any(dev_ids)))        
         .load::<Option<BigDecimal>>(conn)
         .map_err(|a| {
         Error::DatabaseError(format!("Error get rainfalls sum value by one hours to {}", a.to_string()))
     })?;    
 
-    let data:Vec<_> = value_sum.iter().map(|v|v.as_ref().map(|v1|decimal_to_f32(&v1))).collect();
-    
-    Ok(data)
+    let ids = rainfalls::table                
+        .select(rainfalls::device_id)
+        .group_by(rainfalls::device_id)
+        .order_by(rainfalls::device_id)        
+        .filter(rainfalls::quality.eq(QUALITY_GOOD))
+        .filter(rainfalls::create_time.ge(n_time).and(rainfalls::device_id.eq_any(dev_ids)))        
+        .load::<i32>(conn)
+        .map_err(|a| {
+        Error::DatabaseError(format!("Error get rainfalls sum value by one hours to {}", a.to_string()))
+    })?;    
+
+    Ok(by_device(dev_ids, &ids, &value_sum))
 }
 
 pub fn a_rainfalls_by_one(conn:&PgConnection,dev_id:i32) -> Result<(Vec<Option<f32>>,Vec<String>),Error> {
@@ -615,6 +1846,7 @@ pub fn a_rainfalls_by_one(conn:&PgConnection,dev_id:i32) -> Result<(Vec<Option<f
         .select(sum(rainfalls::value))
         .group_by(rainfalls::one_hour)
         .order_by(rainfalls::one_hour)        
+        .filter(rainfalls::quality.eq(QUALITY_GOOD))
         .filter(rainfalls::create_time.ge(n_time).and(rainfalls::device_id.eq(dev_id)))        
         .load::<Option<BigDecimal>>(conn)
         .map_err(|a| {
@@ -625,6 +1857,7 @@ pub fn a_rainfalls_by_one(conn:&PgConnection,dev_id:i32) -> Result<(Vec<Option<f
         .select(rainfalls::one_hour)
         .group_by(rainfalls::one_hour)
         .order_by(rainfalls::one_hour)        
+        .filter(rainfalls::quality.eq(QUALITY_GOOD))
         .filter(rainfalls::create_time.ge(n_time).and(rainfalls::device_id.eq(dev_id)))        
         .load::<i64>(conn)
         .map_err(|a| {
@@ -649,15 +1882,25 @@ pub fn rainfall_by_one_half(conn:&PgConnection,dev_ids:&Vec<i32>) -> Result<Vec<
         .select(sum(rainfalls::value))
         .group_by(rainfalls::device_id)
         .order_by(rainfalls::device_id)        
+        .filter(rainfalls::quality.eq(QUALITY_GOOD))
         .filter(rainfalls::create_time.ge(n_time).and(rainfalls::device_id.eq_any(dev_ids)))        
         .load::<Option<BigDecimal>>(conn)
         .map_err(|a| {
         Error::DatabaseError(format!("Error get rainfalls sum value by one hours to {}", a.to_string()))
     })?;    
 
-    let data:Vec<_> = value_sum.iter().map(|v|v.as_ref().map(|v1|decimal_to_f32(&v1))).collect();
-    
-    Ok(data)
+    let ids = rainfalls::table                
+        .select(rainfalls::device_id)
+        .group_by(rainfalls::device_id)
+        .order_by(rainfalls::device_id)        
+        .filter(rainfalls::quality.eq(QUALITY_GOOD))
+        .filter(rainfalls::create_time.ge(n_time).and(rainfalls::device_id.eq_any(dev_ids)))        
+        .load::<i32>(conn)
+        .map_err(|a| {
+        Error::DatabaseError(format!("Error get rainfalls sum value by one hours to {}", a.to_string()))
+    })?;    
+
+    Ok(by_device(dev_ids, &ids, &value_sum))
 }
 
 pub fn a_rainfalls_by_one_half(conn:&PgConnection,dev_id:i32) -> Result<(Vec<Option<f32>>,Vec<String>),Error> {
@@ -671,6 +1914,7 @@ pub fn a_rainfalls_by_one_half(conn:&PgConnection,dev_id:i32) -> Result<(Vec<Opt
         .select(sum(rainfalls::value))
         .group_by(rainfalls::one_half_hour)
         .order_by(rainfalls::one_half_hour)        
+        .filter(rainfalls::quality.eq(QUALITY_GOOD))
         .filter(rainfalls::create_time.ge(n_time).and(rainfalls::device_id.eq(dev_id)))        
         .load::<Option<BigDecimal>>(conn)
         .map_err(|a| {
@@ -681,6 +1925,7 @@ pub fn a_rainfalls_by_one_half(conn:&PgConnection,dev_id:i32) -> Result<(Vec<Opt
         .select(rainfalls::one_half_hour)
         .group_by(rainfalls::one_half_hour)
         .order_by(rainfalls::one_half_hour)        
+        .filter(rainfalls::quality.eq(QUALITY_GOOD))
         .filter(rainfalls::create_time.ge(n_time).and(rainfalls::device_id.eq(dev_id)))        
         .load::<i64>(conn)
         .map_err(|a| {
@@ -704,15 +1949,25 @@ pub fn rainfall_by_two(conn:&PgConnection,dev_ids:&Vec<i32>) -> Result<Vec<Optio
         .select(sum(rainfalls::value))
         .group_by(rainfalls::device_id)
         .order_by(rainfalls::device_id)        
+        .filter(rainfalls::quality.eq(QUALITY_GOOD))
         .filter(rainfalls::create_time.ge(n_time).and(rainfalls::device_id.eq_any(dev_ids)))        
         .load::<Option<BigDecimal>>(conn)
         .map_err(|a| {
         Error::DatabaseError(format!("Error get rainfalls sum value by one hours to {}", a.to_string()))
     })?;    
 
-    let data:Vec<_> = value_sum.iter().map(|v|v.as_ref().map(|v1|decimal_to_f32(&v1))).collect();
-    
-    Ok(data)
+    let ids = rainfalls::table                
+        .select(rainfalls::device_id)
+        .group_by(rainfalls::device_id)
+        .order_by(rainfalls::device_id)        
+        .filter(rainfalls::quality.eq(QUALITY_GOOD))
+        .filter(rainfalls::create_time.ge(n_time).and(rainfalls::device_id.eq_any(dev_ids)))        
+        .load::<i32>(conn)
+        .map_err(|a| {
+        Error::DatabaseError(format!("Error get rainfalls sum value by one hours to {}", a.to_string()))
+    })?;    
+
+    Ok(by_device(dev_ids, &ids, &value_sum))
 }
 
 pub fn a_rainfalls_by_two(conn:&PgConnection,dev_id:i32) -> Result<(Vec<Option<f32>>,Vec<String>),Error> {
@@ -726,6 +1981,7 @@ pub fn a_rainfalls_by_two(conn:&PgConnection,dev_id:i32) -> Result<(Vec<Option<f
         .select(sum(rainfalls::value))
         .group_by(rainfalls::two_hour)
         .order_by(rainfalls::two_hour)        
+        .filter(rainfalls::quality.eq(QUALITY_GOOD))
         .filter(rainfalls::create_time.ge(n_time).and(rainfalls::device_id.eq(dev_id)))        
         .load::<Option<BigDecimal>>(conn)
         .map_err(|a| {
@@ -736,6 +1992,7 @@ pub fn a_rainfalls_by_two(conn:&PgConnection,dev_id:i32) -> Result<(Vec<Option<f
         .select(rainfalls::two_hour)
         .group_by(rainfalls::two_hour)
         .order_by(rainfalls::two_hour)        
+        .filter(rainfalls::quality.eq(QUALITY_GOOD))
         .filter(rainfalls::create_time.ge(n_time).and(rainfalls::device_id.eq(dev_id)))        
         .load::<i64>(conn)
         .map_err(|a| {
@@ -759,15 +2016,25 @@ pub fn rainfall_by_three(conn:&PgConnection,dev_ids:&Vec<i32>) -> Result<Vec<Opt
         .select(sum(rainfalls::value))
         .group_by(rainfalls::device_id)
         .order_by(rainfalls::device_id)        
+        .filter(rainfalls::quality.eq(QUALITY_GOOD))
         .filter(rainfalls::create_time.ge(n_time).and(rainfalls::device_id.eq_any(dev_ids)))        
         .load::<Option<BigDecimal>>(conn)
         .map_err(|a| {
         Error::DatabaseError(format!("Error get rainfalls sum value by one hours to {}", a.to_string()))
     })?;    
 
-    let data:Vec<_> = value_sum.iter().map(|v|v.as_ref().map(|v1|decimal_to_f32(&v1))).collect();
-    
-    Ok(data)
+    let ids = rainfalls::table                
+        .select(rainfalls::device_id)
+        .group_by(rainfalls::device_id)
+        .order_by(rainfalls::device_id)        
+        .filter(rainfalls::quality.eq(QUALITY_GOOD))
+        .filter(rainfalls::create_time.ge(n_time).and(rainfalls::device_id.eq_any(dev_ids)))        
+        .load::<i32>(conn)
+        .map_err(|a| {
+        Error::DatabaseError(format!("Error get rainfalls sum value by one hours to {}", a.to_string()))
+    })?;    
+
+    Ok(by_device(dev_ids, &ids, &value_sum))
 }
 
 pub fn a_rainfalls_by_three(conn:&PgConnection,dev_id:i32) -> Result<(Vec<Option<f32>>,Vec<String>),Error> {
@@ -781,6 +2048,7 @@ pub fn a_rainfalls_by_three(conn:&PgConnection,dev_id:i32) -> Result<(Vec<Option
         .select(sum(rainfalls::value))
         .group_by(rainfalls::three_hour)
         .order_by(rainfalls::three_hour)        
+        .filter(rainfalls::quality.eq(QUALITY_GOOD))
         .filter(rainfalls::create_time.ge(n_time).and(rainfalls::device_id.eq(dev_id)))        
         .load::<Option<BigDecimal>>(conn)
         .map_err(|a| {
@@ -791,6 +2059,7 @@ pub fn a_rainfalls_by_three(conn:&PgConnection,dev_id:i32) -> Result<(Vec<Option
         .select(rainfalls::three_hour)
         .group_by(rainfalls::three_hour)
         .order_by(rainfalls::three_hour)        
+        .filter(rainfalls::quality.eq(QUALITY_GOOD))
         .filter(rainfalls::create_time.ge(n_time).and(rainfalls::device_id.eq(dev_id)))        
         .load::<i64>(conn)
         .map_err(|a| {
@@ -803,6 +2072,28 @@ pub fn a_rainfalls_by_three(conn:&PgConnection,dev_id:i32) -> Result<(Vec<Option
     Ok((value_sum,times))
 }
 
+pub fn depth_channels(conn:&PgConnection,dev_id:i32) -> Result<Vec<String>,Error> {
+    use super::schema::water_depths::dsl::*;
+    water_depths
+        .select(channel)
+        .distinct()
+        .filter(device_id.eq(dev_id))
+        .order_by(channel)
+        .load::<String>(conn)
+        .map_err(|e| Error::DatabaseError(format!("Error get depth channels to {}", e.to_string())))
+}
+
+// the requested channel, else the primary one, else all of the device's channels
+fn chart_channels(conn:&PgConnection,dev_id:i32,requested:Option<&str>) -> Result<Vec<String>,Error> {
+    if let Some(c) = requested {
+        return Ok(vec![c.to_string()]);
+    }
+    match get_device(conn, dev_id)?.primary_channel {
+        Some(p) => Ok(vec![p]),
+        None => depth_channels(conn, dev_id),
+    }
+}
+
 fn decimal_to_f32(v:&BigDecimal) -> f32 {
     v.with_scale(2).to_f32().unwrap_or(0.0)
 }
@@ -811,15 +2102,20 @@ pub fn water_depth_of_recently(conn:&PgConnection,dev_ids:&Vec<i32>) -> Result<V
     use super::schema::*;
     let mut datas:Vec<f32> = vec![];
     for dev_id in dev_ids {
+        let channels = chart_channels(conn, *dev_id, None)?;
         let data=water_depths::table
             .select(water_depths::value)
             .filter(water_depths::device_id.eq(dev_id))
+            .filter(water_depths::channel.eq_any(&channels))
+            .filter(water_depths::quality.eq(QUALITY_GOOD))
             .order_by(water_depths::create_time.desc())
             .first::<BigDecimal>(conn)
+            .optional()
             .map_err(|a| {
                 Error::DatabaseError(format!("Error get water depth value by one hours to {}", a.to_string()))
             })?;
-        let data = decimal_to_f32(&data);
+        // a station without a good depth is still listed
+        let data = data.map(|d| decimal_to_f32(&d)).unwrap_or(0.0);
         datas.push(data);
     }
     Ok(datas)
@@ -844,4 +2140,98 @@ pub fn update_devices_swidth(conn:&PgConnection) {
         
     }
 
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn device_time_within_bounds_is_kept() {
+        assert_eq!(ReadingTime::new(Some(1_622_505_000), 1_622_505_600, 86400).observed, 1_622_505_000);
+        assert_eq!(ReadingTime::new(Some(1_622_505_900), 1_622_505_600, 86400).observed, 1_622_505_900);
+        assert_eq!(ReadingTime::new(None, 1_622_505_600, 86400).observed, 1_622_505_600);
+    }
+
+    #[test]
+    fn device_time_out_of_bounds_is_receive_time() {
+        let received = 1_622_505_600;
+        // ahead of the server clock
+        assert_eq!(ReadingTime::new(Some(received+3600), received, 86400).observed, received);
+        // a clock reset to 0 or 2000
+        assert_eq!(ReadingTime::new(Some(0), received, 86400).observed, received);
+        assert_eq!(ReadingTime::new(Some(946_656_000), received, 86400).observed, received);
+        let time = ReadingTime::new(Some(-100_000_000_000_000), received, 86400);
+        assert_eq!(time.observed, received);
+        assert_eq!(time.observed_time().timestamp(), received);
+    }
+
+    fn close(a:f32,b:f32) -> bool {
+        (a - b).abs() < 1e-4
+    }
+
+    #[test]
+    fn first_counter_is_baseline() {
+        assert_eq!(rain_increment(None, 123.4, 600, None), (0.0, CounterEvent::Baseline));
+    }
+
+    #[test]
+    fn counter_increase_is_rainfall() {
+        let (value, event) = rain_increment(Some((10.0, 0)), 12.5, 300, None);
+        assert!(close(value, 2.5));
+        assert_eq!(event, CounterEvent::Normal);
+        assert_eq!(rain_increment(Some((10.0, 0)), 10.0, 300, None), (0.0, CounterEvent::Normal));
+    }
+
+    #[test]
+    fn small_drop_is_jitter() {
+        assert_eq!(rain_increment(Some((10.0, 0)), 9.97, 300, None), (0.0, CounterEvent::Jitter));
+        assert_eq!(rain_increment(Some((10.0, 0)), 9.97, 300, Some(100.0)), (0.0, CounterEvent::Jitter));
+        assert!(!CounterEvent::Jitter.moves_baseline());
+    }
+
+    #[test]
+    fn jitter_oscillation_adds_no_rain() {
+        // the baseline only follows readings that move it
+        let mut baseline = Some((10.0, 0));
+        let mut total = 0.0;
+        for (i, counter) in [9.97, 10.0, 9.97, 10.0, 9.97, 10.0].iter().enumerate() {
+            let observed = 300*(i as i64+1);
+            let (value, event) = rain_increment(baseline, *counter, observed, None);
+            total += value;
+            if event.moves_baseline() {
+                baseline = Some((*counter, observed));
+            }
+        }
+        assert_eq!(total, 0.0);
+    }
+
+    #[test]
+    fn older_reading_is_late() {
+        // a buffered reading with a lower counter isn't a reset
+        assert_eq!(rain_increment(Some((10.0, 600)), 2.0, 300, None), (0.0, CounterEvent::Late));
+        assert_eq!(rain_increment(Some((10.0, 600)), 12.0, 300, None), (0.0, CounterEvent::Late));
+        assert!(!CounterEvent::Late.moves_baseline());
+    }
+
+    #[test]
+    fn drop_is_reset() {
+        let (value, event) = rain_increment(Some((10.0, 0)), 2.0, 300, None);
+        assert!(close(value, 2.0));
+        assert_eq!(event, CounterEvent::Reset);
+        // far from counter_max a drop is still a reset
+        assert_eq!(rain_increment(Some((50.0, 0)), 2.0, 300, Some(100.0)).1, CounterEvent::Reset);
+    }
+
+    #[test]
+    fn drop_near_counter_max_is_rollover() {
+        let (value, event) = rain_increment(Some((99.5, 0)), 0.7, 300, Some(100.0));
+        assert!(close(value, 1.2));
+        assert_eq!(event, CounterEvent::Rollover);
+    }
+    #[test]
+    fn values_follow_device_order() {
+        // device 2 has no good readings in the window, the others keep their places
+        let values = vec![None, Some(BigDecimal::from(1.5))];
+        assert_eq!(by_device(&[1, 2, 3], &[1, 3], &values), vec![None, None, Some(1.5)]);
+    }
+}
@@ -1,243 +1,516 @@
-use toml;
-use std::fs::File;
-use std::io::Read;
-use serde_derive::{Deserialize,Serialize};
-use serde_json;
+use chrono::prelude::*;
 
-use super::models;
+use super::config::{BrokerConfig, Config};
+use super::error::Error;
+use super::ingest::{self, Ingestor, PayloadFormat};
+use super::models::{self, PgPool};
+use super::queue::{Backpressure, Job, WorkQueue};
+use super::replay::{self, RawRecord};
+use super::topic::{self, TopicTemplate};
+use diesel::pg::PgConnection;
 use paho_mqtt as mqtt;
-use std::{collections::HashMap, process, thread, time::Duration,sync::{RwLock,RwLockWriteGuard,RwLockReadGuard}};
+use std::{collections::HashSet, thread, time::{Duration, Instant}};
+use std::sync::{Arc, Mutex, atomic::{AtomicBool, AtomicI64, AtomicUsize, Ordering}};
 
-#[derive(Debug,Deserialize)]
-pub struct Config {
-    qos:Option<i32>,
-    mqtt_host:Option<String>,
-    client_id:Option<String>,
-    user_name:Option<String>,
-    password:Option<String>,
-}
-
-impl Config {
-    pub fn new() -> Self {
-        deser_toml()
-    }
-    pub fn qos(&self) -> i32 {
-        match self.qos {
-            Some(q) => q,
-            None => 1,
-        }
-    }
-    
-    pub fn host(&self) -> &str {
-        match self.mqtt_host.as_ref() {
-            Some(h) => &h,
-            None => "",
-        }
-    }
+const POOL_LOG_SECS:u64 = 60;
+const ARCHIVE_PURGE_SECS:u64 = 3600;
+const STATUS_CHECK_SECS:u64 = 60;
+// longest wait between database retries of a queued job
+const DB_RETRY_MAX_SECS:u64 = 30;
 
-    pub fn client_id(&self) -> &str {
-        match self.client_id.as_ref() {
-            Some(id) => &id,
-            None => "",
-        }
-    }
+// one per broker connection, the pool and work queue are shared
+struct ClientData {
+    broker:BrokerConfig,
+    default_broker:bool,
+    format:PayloadFormat,
+    templates:Vec<TopicTemplate>,
+    pool:PgPool,
+    subscribed:Mutex<HashSet<String>>,
+    queue:Arc<WorkQueue>,
+    running:Arc<AtomicBool>,
+    in_flight:AtomicUsize,
+    last_message:AtomicI64,
+}
 
-    pub fn user_name(&self) -> &str {
-        match self.user_name.as_ref() {
-            Some(u) => &u,
-            None => "",
-        }
-    }
+// counts a message as in flight until it has been queued
+struct InFlight<'a>(&'a AtomicUsize);
 
-    pub fn password(&self) -> &str {
-        match self.password.as_ref() {
-            Some(p) => &p,
-            None => "",
-        }
+impl<'a> InFlight<'a> {
+    fn new(count:&'a AtomicUsize) -> InFlight<'a> {
+        count.fetch_add(1, Ordering::SeqCst);
+        InFlight(count)
     }
-    
 }
 
-fn deser_toml() -> Config {
-    let mut toml_str = String::new();
-    File::open("Config.toml")
-        .and_then(|mut f| f.read_to_string(&mut toml_str))
-        .unwrap();
-    
-    toml::from_str(&toml_str).unwrap()    
+impl<'a> Drop for InFlight<'a> {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
 }
-const DEPTH_POINT:[&str;33] = ["44271","44275","44279","44283","44287","44291","44295","44299","44303",
-                    "44307","44311","44315","44319","44323","44327","44331","44335","44339",
-                    "44343","44347","44351","44355","44359","44363","44367","44371","44375",
-                    "44379","44383","44387","44391","44395","44399"
-];
 
-#[derive(Serialize, Deserialize, Debug)]
-pub struct PayloadData {
-    #[serde(rename="dataPoints")]
-    data_points:Vec<DataPoint>,
-    #[serde(rename="devName")]
-    dev_name:String
+fn on_connect_success(cli: &mqtt::AsyncClient, _msgid: u16) {
+    let data = get_client_data(cli);
+    println!("[{}] Connection succeeded", data.broker.name());
+    // the watchdog counts the silence of this connection, not of the outage before it
+    data.last_message.store(Utc::now().timestamp(), Ordering::SeqCst);
+    let conn = match data.pool.get() {
+        Ok(c) => c,
+        Err(e) => {
+            println!("Get database connection failed: {}", e);
+            return;
+        }
+    };
+    if let Ok(topics) = wanted_topics(&conn, data) {        
+        let qos = vec![data.broker.qos(); topics.len()];
+        cli.subscribe_many(&topics, &qos);
+        *data.subscribed.lock().unwrap() = topics.into_iter().collect();
+    }    
 }
 
-#[derive(Serialize, Deserialize, Debug)]
-struct DataPoint {
-    #[serde(rename="pointId")]
-    point_id:String,
-    value:String,
-    #[serde(rename="slaveIndex")]
-    slave_index:String,
-    #[serde(rename="slaveAddr")]
-    slave_addr:String,
+// device topics plus the last will wildcard
+fn wanted_topics(conn:&PgConnection,data:&ClientData) -> Result<Vec<String>,Error> {
+    let mut topics = vec![];
+    for key in models::broker_devices(conn, data.broker.name(), data.default_broker)? {
+        if !topic::valid_device_key(&key) {
+            println!("[{}] Device {} can't be used in a topic, skipped", data.broker.name(), key);
+            continue;
+        }
+        topics.extend(data.templates.iter().map(|t| t.subscription(&key)));
+    }
+    if let Some(lwt) = data.broker.lwt_topic() {
+        topics.push(format!("{}+", lwt));
+    }
+    Ok(topics)
 }
 
-impl From<String> for PayloadData {
-    fn from(payload:String) -> PayloadData {
-        let payload_data:PayloadData =serde_json::from_str(&payload)
-        .unwrap_or(PayloadData{
-            data_points:vec![],
-            dev_name:"".to_string(),
-        });
-        payload_data
+// devices naming a broker that isn't configured are never subscribed; each is logged once
+// until its broker is fixed
+fn check_unassigned_devices(pool:&PgPool,broker_names:&[&str],reported:&mut HashSet<(String,String)>) {
+    let conn = match pool.get() {
+        Ok(c) => c,
+        Err(e) => {
+            println!("Get database connection failed: {}", e);
+            return;
+        }
+    };
+    match models::unassigned_devices(&conn, broker_names) {
+        Ok(found) => {
+            for (key, broker) in &found {
+                if !reported.contains(&(key.clone(), broker.clone())) {
+                    println!("Device {} names broker {}, which isn't configured; it is not subscribed", key, broker);
+                }
+            }
+            *reported = found.into_iter().collect();
+        }
+        Err(e) => println!("{}", e.to_string()),
     }
 }
 
-impl DataPoint {
-    fn is_rainfall(&self) -> bool {
-        if self.point_id == "44266" {
-            return true;
+// subscribe topics of newly added devices and drop those of removed ones
+fn sync_subscriptions(cli: &mqtt::AsyncClient) {
+    let data = get_client_data(cli);
+    let conn = match data.pool.get() {
+        Ok(c) => c,
+        Err(e) => {
+            println!("Get database connection failed: {}", e);
+            return;
         }
-        false
-    }
-    fn is_depth(&self) -> bool {
-        DEPTH_POINT.contains(&self.point_id.as_str())
+    };
+    let topics:HashSet<String> = match wanted_topics(&conn, data) {
+        Ok(t) => t.into_iter().collect(),
+        Err(e) => {
+            println!("{}", e.to_string());
+            return;
+        }
+    };
+    let mut subscribed = data.subscribed.lock().unwrap();
+    for topic in topics.difference(&subscribed) {
+        println!("[{}] Device added, subscribe {}", data.broker.name(), topic);
+        cli.subscribe(topic.as_str(), data.broker.qos());
     }
-    fn get_value(&self) -> f32 {
-        self.value.parse().unwrap_or(0.0f32)
+    for topic in subscribed.difference(&topics) {
+        println!("[{}] Device removed, unsubscribe {}", data.broker.name(), topic);
+        cli.unsubscribe(topic.as_str());
     }
-}
-
-type DataHasp = RwLock<HashMap<String, f32>>;
-
-fn on_connect_success(cli: &mqtt::AsyncClient, _msgid: u16) {
-    println!("Connection succeeded");
-    if let Ok(topics) = models::topics() {        
-        let qos = Config::new().qos();
-        let qos = vec![qos; topics.len()];
-        cli.subscribe_many(&topics, &qos);
-    }    
+    *subscribed = topics;
 }
 
 fn on_connect_failure(cli: &mqtt::AsyncClient, _msgid: u16, rc: i32) {
-    println!("Connection attempt failed with error code {}.\n", rc);
+    let data = get_client_data(cli);
+    println!("[{}] Connection attempt failed with error code {}.\n", data.broker.name(), rc);
+    if !data.running.load(Ordering::SeqCst) {
+        return;
+    }
     thread::sleep(Duration::from_millis(2500));
     cli.reconnect_with_callbacks(on_connect_success, on_connect_failure);
 }
 
+// only queues the message, the database work runs on the queue workers
 fn on_message(cli: &mqtt::AsyncClient, msg: Option<mqtt::Message>) {       
     if let Some(msg) = msg {
-        let payload_str = msg.payload_str().to_string();
+        let received = Utc::now().timestamp();
+        let payload = msg.payload_str().to_string();
         let topic = msg.topic();
-        let payload = PayloadData::from(payload_str);
-        let conn = models::db_connection();
-        if conn.is_err() {
-            return;
+        let data = get_client_data(cli);
+        let _in_flight = InFlight::new(&data.in_flight);
+        data.last_message.store(received, Ordering::SeqCst);
+        let job = match data.broker.lwt_topic() {
+            Some(lwt) if topic.starts_with(lwt) => Job::Will {
+                device_key:topic[lwt.len()..].to_string(),
+            },
+            _ => match data.templates.iter().find_map(|t| t.device_key(topic)) {
+                Some(key) => Job::Message {
+                    topic:topic.to_string(),
+                    device_key:key.to_string(),
+                    payload,
+                    format:data.format,
+                    received,
+                },
+                None => {
+                    let templates:Vec<&str> = data.templates.iter().map(|t| t.as_str()).collect();
+                    Job::Rejected {
+                        topic:topic.to_string(),
+                        payload,
+                        reason:format!("topic matches none of {}", templates.join(", ")),
+                        received,
+                    }
+                }
+            },
+        };
+        if let Err(e) = data.queue.push(job) {
+            println!("[{}] {}, message on {} dropped", data.broker.name(), e.to_string(), topic);
         }
-        let conn = conn.unwrap();
-        let dev_id = device_id_in_topic(topic);
-        let dev_id = models::get_id_by_deviceid(&conn, dev_id.to_string());
-        if dev_id.is_err() {
-            return;
+    }
+}
+
+// a database outage holds the worker, and with it the order of its devices, until the
+// database is back; once shutting down, messages go to the lost message file for replay_run
+fn process_job(pool:&PgPool,ingestor:&Ingestor,running:&AtomicBool,lost_file:&str,job:Job) {
+    let mut delay = 1;
+    let conn = loop {
+        if !running.load(Ordering::SeqCst) {
+            match pool.try_get() {
+                Some(c) => break c,
+                None => {
+                    save_lost_job(lost_file, job);
+                    return;
+                }
+            }
         }
-        let dev_id = dev_id.unwrap();
-        for point in payload.data_points {
-            if point.is_rainfall() {
-                store_rainfall(dev_id, &point, cli, topic);                             
-                
+        match pool.get() {
+            Ok(c) => break c,
+            Err(e) => {
+                println!("Get database connection failed: {}, job of {} retried in {}s", e, job.order_key(), delay);
+                for _ in 0..delay {
+                    if !running.load(Ordering::SeqCst) {
+                        break;
+                    }
+                    thread::sleep(Duration::from_secs(1));
+                }
+                delay = (delay*2).min(DB_RETRY_MAX_SECS);
             }
-            if point.is_depth() {
-                store_water_depth(dev_id, &point);
-            }                     
-        }                      
+        }
+    };
+    match job {
+        Job::Message { topic, device_key, payload, format, received } => {
+            ingestor.ingest(&conn, &topic, &device_key, &payload, format, received).ok();
+        }
+        Job::Will { device_key } => device_will(&conn, &device_key),
+        Job::Rejected { topic, payload, reason, received } => {
+            ingest::reject_message(&conn, &topic, &payload, &reason, received);
+        }
     }
-    
-    
 }
-fn store_rainfall(dev_id:i32,point:&DataPoint,cli: &mqtt::AsyncClient,topic:&str) {
-    {
-        let new_value = point.get_value();
-        let hasp_read_data = get_user_read_data(cli);
-        let value = hasp_read_data.get(topic).unwrap_or(&new_value);        
-        let rainfall_value = (new_value-value).max(0.0);        
-        models::new_rainfall(dev_id, rainfall_value).unwrap_or_default();
-        models::new_calculation(dev_id, rainfall_value).unwrap_or_default();
-          
-    }                   
-    
-    {
-        let mut hasp_write_data = get_user_write_data(cli);
-        hasp_write_data.insert(topic.to_string(), point.get_value());
-    } 
 
+fn save_lost_job(lost_file:&str,job:Job) {
+    match job {
+        Job::Message { topic, payload, received, .. } => {
+            let record = RawRecord { topic, payload, time:received };
+            match replay::append_jsonl(lost_file, &record) {
+                Ok(_) => println!("Database unavailable, message on {} saved to {}", record.topic, lost_file),
+                Err(e) => println!("Database unavailable, message on {} lost: {}", record.topic, e.to_string()),
+            }
+        }
+        Job::Will { device_key } => println!("Database unavailable, last will of {} lost", device_key),
+        Job::Rejected { topic, reason, .. } => println!("Database unavailable, rejected message on {} ({}) lost", topic, reason),
+    }
 }
 
-fn store_water_depth(dev_id:i32,poit:&DataPoint) {
-    let value = poit.get_value();    
-    models::new_water_depth(dev_id, value).unwrap_or_default();
+// the broker published the device's last will: it dropped off without disconnecting
+fn device_will(conn:&PgConnection,device_key:&str) {
+    let marked = models::get_id_by_deviceid(conn, device_key.to_string())
+        .and_then(|dev_id| models::mark_device_offline(conn, dev_id, "lwt"));
+    match marked {
+        Ok(count) if count > 0 => println!("Device {} offline (last will)", device_key),
+        Ok(_) => {}
+        Err(e) => println!("{}", e.to_string()),
+    }
+}
 
+fn check_device_status(pool:&PgPool,offline_after_secs:i64) {
+    let marked = pool.get()
+        .map_err(|e| Error::DatabaseError(e.to_string()))
+        .and_then(|conn| models::mark_silent_devices_offline(&conn, offline_after_secs));
+    match marked {
+        Ok(count) if count > 0 => println!("{} devices went offline", count),
+        Ok(_) => {}
+        Err(e) => println!("{}", e.to_string()),
+    }
 }
 
-fn device_id_in_topic(topic:&str) -> &str {
-    topic.split_at(15).1
+fn purge_archive(pool:&PgPool,retention_days:i64,checkpoint_retention_days:i64) {
+    let conn = match pool.get() {
+        Ok(c) => c,
+        Err(e) => {
+            println!("Get database connection failed: {}", e);
+            return;
+        }
+    };
+    match models::purge_raw_messages(&conn, retention_days) {
+        Ok(count) if count > 0 => println!("Purged {} archived messages", count),
+        Ok(_) => {}
+        Err(e) => println!("{}", e.to_string()),
+    }
+    match models::purge_fingerprints(&conn, retention_days) {
+        Ok(count) if count > 0 => println!("Purged {} message fingerprints", count),
+        Ok(_) => {}
+        Err(e) => println!("{}", e.to_string()),
+    }
+    match models::purge_checkpoints(&conn, checkpoint_retention_days) {
+        Ok(count) if count > 0 => println!("Purged {} model checkpoints", count),
+        Ok(_) => {}
+        Err(e) => println!("{}", e.to_string()),
+    }
 }
 
-fn get_user_write_data(cli: &mqtt::AsyncClient) -> RwLockWriteGuard<HashMap<String, f32>> {
+fn get_client_data(cli: &mqtt::AsyncClient) -> &ClientData {
     let data = cli.user_data().unwrap();
-    let lock = data.downcast_ref::<DataHasp>().unwrap();
-    lock.write().unwrap()    
+    data.downcast_ref::<ClientData>().unwrap()
 }
 
-fn get_user_read_data(cli: &mqtt::AsyncClient) -> RwLockReadGuard<HashMap<String, f32>> {
-    let data = cli.user_data().unwrap();
-    let lock = data.downcast_ref::<DataHasp>().unwrap();
-    lock.read().unwrap()
+
+// restart the session when the broker went silent although the connection looks alive
+fn watchdog(cli: &mqtt::AsyncClient, watchdog_secs:u64) {
+    let data = get_client_data(cli);
+    let now = Utc::now().timestamp();
+    let last = data.last_message.load(Ordering::SeqCst);
+    if watchdog_secs == 0 || now-last < watchdog_secs as i64 {
+        return;
+    }
+    // a lost connection is already being retried by the connection callbacks
+    if !cli.is_connected() {
+        return;
+    }
+    println!("[{}] No message for {} seconds, restarting connection", data.broker.name(), now-last);
+    data.last_message.store(now, Ordering::SeqCst);
+    let disconn_opts = mqtt::DisconnectOptionsBuilder::new()
+        .timeout(Duration::from_secs(5))
+        .finalize();
+    if let Err(e) = cli.disconnect(disconn_opts).wait_for(Duration::from_secs(10)) {
+        println!("Disconnect failed: {:?}", e);
+    }
+    cli.reconnect_with_callbacks(on_connect_success, on_connect_failure);
 }
 
-pub fn run_mqtt_client() {
-    let config = Config::new();
-    let hash_datas: HashMap<String, f32> = HashMap::new();    
+// unsubscribe, wait for in-flight messages to be queued, then disconnect
+fn shutdown(cli: &mqtt::AsyncClient, timeout_secs:u64) -> Result<(),Error> {
+    let data = get_client_data(cli);
+    let timeout = Duration::from_secs(timeout_secs);
+    if cli.is_connected() {
+        let topics:Vec<String> = data.subscribed.lock().unwrap().drain().collect();
+        if !topics.is_empty() {
+            println!("[{}] Unsubscribe {} topics", data.broker.name(), topics.len());
+            if let Err(e) = cli.unsubscribe_many(&topics).wait_for(timeout) {
+                println!("Unsubscribe failed: {:?}", e);
+            }
+        }
+    }
+    let started = Instant::now();
+    while data.in_flight.load(Ordering::SeqCst) > 0 && started.elapsed() < timeout {
+        thread::sleep(Duration::from_millis(100));
+    }
+    let pending = data.in_flight.load(Ordering::SeqCst);
+    if cli.is_connected() {
+        let disconn_opts = mqtt::DisconnectOptionsBuilder::new()
+            .timeout(timeout)
+            .finalize();
+        cli.disconnect(disconn_opts).wait_for(timeout)
+            .map_err(|e| Error::MqttError(format!("Error disconnect to {:?}", e)))?;
+    }
+    if pending > 0 {
+        return Err(Error::MqttError(format!("{} messages still in flight at shutdown of {}", pending, data.broker.name())));
+    }
+    println!("[{}] Disconnected", data.broker.name());
+    Ok(())
+}
+
+// the first broker also takes the devices that have no broker assigned
+fn start_client(broker:BrokerConfig,default_broker:bool,pool:&PgPool,queue:&Arc<WorkQueue>,running:&Arc<AtomicBool>) -> Result<mqtt::AsyncClient,Error> {
+    let conn_opts = connect_options(&broker)?;
+    let templates = broker.topics().into_iter()
+        .map(TopicTemplate::parse)
+        .collect::<Result<Vec<_>,Error>>()?;
     let create_opts = mqtt::CreateOptionsBuilder::new()
-        .server_uri(config.host())
-        .client_id(config.client_id())
-        .user_data(Box::new(RwLock::new(hash_datas)))                
+        .server_uri(broker.host())
+        .client_id(broker.client_id())
+        .user_data(Box::new(ClientData {
+            format:PayloadFormat::parse(broker.payload_format())?,
+            templates,
+            broker,
+            default_broker,
+            pool:pool.clone(),
+            subscribed:Mutex::new(HashSet::new()),
+            queue:queue.clone(),
+            running:running.clone(),
+            in_flight:AtomicUsize::new(0),
+            last_message:AtomicI64::new(Utc::now().timestamp()),
+        }))                
         .finalize();
 
-    let mut cli = mqtt::AsyncClient::new(create_opts).unwrap_or_else(|e| {
-        println!("Error creating the client: {:?}", e);
-        process::exit(1);
-    });
+    let mut cli = mqtt::AsyncClient::new(create_opts)
+        .map_err(|e| Error::MqttError(format!("Error creating the client to {:?}", e)))?;
 
-    cli.set_connected_callback(|_cli| {
-        println!("Connected.");
+    cli.set_connected_callback(|cli| {
+        println!("[{}] Connected.", get_client_data(cli).broker.name());
     });
 
     cli.set_connection_lost_callback(|cli| {
-        println!("Connection lost. Attempting reconnect.");
+        let data = get_client_data(cli);
+        if !data.running.load(Ordering::SeqCst) {
+            return;
+        }
+        println!("[{}] Connection lost. Attempting reconnect.", data.broker.name());
         thread::sleep(Duration::from_millis(2500));
         cli.reconnect_with_callbacks(on_connect_success, on_connect_failure);
     });
 
     cli.set_message_callback(on_message);
 
-    let conn_opts = mqtt::ConnectOptionsBuilder::new()
-        .user_name(config.user_name())
-        .password(md5_password(config.password()))
-        .finalize();
-
     cli.connect_with_callbacks(conn_opts, on_connect_success, on_connect_failure);
-    loop {
+    Ok(cli)
+}
+
+pub fn run_mqtt_client(pool:PgPool) -> Result<(),Error> {
+    let config = Config::new();
+    let running = Arc::new(AtomicBool::new(true));
+    let handler_running = running.clone();
+    ctrlc::set_handler(move || {
+        println!("Shutdown requested");
+        handler_running.store(false, Ordering::SeqCst);
+    }).map_err(|e| Error::MqttError(format!("Error set signal handler to {}", e)))?;
+
+    let ingestor = Arc::new(Ingestor::new(&config));
+    let worker_pool = pool.clone();
+    let worker_ingestor = ingestor.clone();
+    let worker_running = running.clone();
+    let lost_file = config.lost_message_file().to_string();
+    let queue = Arc::new(WorkQueue::start(
+        config.ingest_workers(),
+        config.queue_capacity(),
+        Backpressure::parse(config.queue_policy())?,
+        move |job| process_job(&worker_pool, &worker_ingestor, &worker_running, &lost_file, job),
+    ));
+    let mut clients = vec![];
+    for (i, broker) in config.brokers().into_iter().enumerate() {
+        clients.push(start_client(broker, i == 0, &pool, &queue, &running)?);
+    }
+    let brokers = config.brokers();
+    let broker_names:Vec<&str> = brokers.iter().map(|b| b.name()).collect();
+    let mut unassigned = HashSet::new();
+    check_unassigned_devices(&pool, &broker_names, &mut unassigned);
+    let mut ticks = 0u64;
+    while running.load(Ordering::SeqCst) {
         thread::sleep(Duration::from_millis(1000));
+        ticks += 1;
+        if ticks % POOL_LOG_SECS == 0 {
+            let state = pool.state();
+            println!("Database pool: {} connections, {} idle, max {}", state.connections, state.idle_connections, pool.max_size());
+            println!("Duplicate messages dropped: {}", ingestor.duplicates());
+            let stats = queue.stats();
+            println!("Work queue: {} waiting, {} on the busiest worker, {} processed, {} dropped", stats.depth, stats.max_worker_depth, stats.processed, stats.dropped);
+        }
+        if ticks % config.device_refresh_secs() == 0 {
+            check_unassigned_devices(&pool, &broker_names, &mut unassigned);
+        }
+        for cli in &clients {
+            if ticks % config.device_refresh_secs() == 0 && cli.is_connected() {
+                sync_subscriptions(cli);
+            }
+            watchdog(cli, config.watchdog_secs());
+        }
+        if ticks % STATUS_CHECK_SECS == 0 {
+            check_device_status(&pool, config.offline_after_secs());
+        }
+        if ticks % ARCHIVE_PURGE_SECS == 0 {
+            purge_archive(&pool, config.archive_retention_days(), config.checkpoint_retention_days());
+        }
+    }
+    // every client is shut down even when one of them fails
+    let mut result = Ok(());
+    for cli in &clients {
+        if let Err(e) = shutdown(cli, config.shutdown_timeout_secs()) {
+            println!("{}", e.to_string());
+            result = Err(e);
+        }
+    }
+    // the clients stopped receiving, store what is still queued
+    if let Err(e) = queue.shutdown(Duration::from_secs(config.shutdown_timeout_secs())) {
+        println!("{}", e.to_string());
+        result = Err(e);
+    }
+    result
+}
+
+fn connect_options(config:&BrokerConfig) -> Result<mqtt::ConnectOptions,Error> {
+    let password = match config.password_mode() {
+        "md5" => md5_password(config.password()),
+        "plain" => config.password().to_string(),
+        other => return Err(Error::MqttError(format!("unknown password_mode {}", other))),
+    };
+    if config.client_key().is_some() && config.client_cert().is_none() {
+        return Err(Error::MqttError(format!("broker {}: client_key needs client_cert", config.name())));
+    }
+    let v5 = config.mqtt_version() == 5;
+    let mut conn_builder = if v5 { mqtt::ConnectOptionsBuilder::new_v5() } else { mqtt::ConnectOptionsBuilder::new() };
+    conn_builder
+        .mqtt_version(config.mqtt_version())
+        .keep_alive_interval(Duration::from_secs(config.keep_alive_secs()))
+        .user_name(config.user_name())
+        .password(password);
+    // MQTT 5 replaced clean_session with clean_start
+    if v5 {
+        conn_builder.clean_start(config.clean_session());
+    } else {
+        conn_builder.clean_session(config.clean_session());
+    }
+    // a TLS host needs SSL options even when it is verified against the default trust store
+    if tls_host(config.host()) || config.ca_file().is_some() || config.client_cert().is_some() {
+        conn_builder.ssl_options(ssl_options(config)?);
+    }
+    Ok(conn_builder.finalize())
+}
+
+fn tls_host(host:&str) -> bool {
+    let host = host.to_ascii_lowercase();
+    ["ssl://", "wss://", "mqtts://"].iter().any(|scheme| host.starts_with(scheme))
+}
+
+fn ssl_options(config:&BrokerConfig) -> Result<mqtt::SslOptions,Error> {
+    let ssl_error = |e:mqtt::Error| Error::MqttError(format!("Error load TLS files to {:?}", e));
+    let mut ssl_builder = mqtt::SslOptionsBuilder::new();
+    if let Some(ca) = config.ca_file() {
+        ssl_builder.trust_store(ca).map_err(ssl_error)?;
+    }
+    if let Some(cert) = config.client_cert() {
+        ssl_builder.key_store(cert).map_err(ssl_error)?;
+        if let Some(key) = config.client_key() {
+            ssl_builder.private_key(key).map_err(ssl_error)?;
+        }
+        if let Some(key_password) = config.client_key_password() {
+            ssl_builder.private_key_password(key_password);
+        }
     }
+    Ok(ssl_builder.finalize())
 }
 
 fn md5_password(password: &str) -> String {
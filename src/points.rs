@@ -0,0 +1,136 @@
+use diesel::pg::PgConnection;
+use std::{sync::RwLock, time::{Duration, Instant}};
+
+use super::error::Error;
+use super::models::{self, SensorPoint};
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum PointKind {
+    Rain,
+    RainDelta,
+    Depth,
+    Voltage,
+    Solar,
+    Signal,
+    Temperature,
+    Other(String),
+}
+
+impl From<&str> for PointKind {
+    fn from(kind:&str) -> PointKind {
+        match kind {
+            "rain" => PointKind::Rain,
+            "rain_delta" => PointKind::RainDelta,
+            "depth" => PointKind::Depth,
+            "voltage" => PointKind::Voltage,
+            "solar" => PointKind::Solar,
+            "signal" => PointKind::Signal,
+            "temperature" => PointKind::Temperature,
+            other => PointKind::Other(other.to_string()),
+        }
+    }
+}
+
+impl PointKind {
+    // housekeeping points stored in device_health under this metric name
+    pub fn health_metric(&self) -> Option<&'static str> {
+        match self {
+            Self::Voltage => Some("voltage"),
+            Self::Solar => Some("solar"),
+            Self::Signal => Some("signal"),
+            Self::Temperature => Some("temperature"),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct PointMapping {
+    pub kind:PointKind,
+    pub scale:f32,
+    pub offset:f32,
+    pub channel:Option<String>,
+}
+
+impl PointMapping {
+    pub fn apply(&self, raw:f32) -> f32 {
+        raw*self.scale + self.offset
+    }
+}
+
+impl From<&SensorPoint> for PointMapping {
+    fn from(point:&SensorPoint) -> PointMapping {
+        PointMapping {
+            kind:PointKind::from(point.kind.as_str()),
+            scale:point.scale_def(),
+            offset:point.offset_def(),
+            channel:point.channel.clone(),
+        }
+    }
+}
+
+// sensor_points cache, reloaded from the database once it is older than reload_interval
+pub struct PointRegistry {
+    points:RwLock<Vec<SensorPoint>>,
+    loaded:RwLock<Option<Instant>>,
+    reload_interval:Duration,
+}
+
+impl PointRegistry {
+    pub fn new(reload_secs:u64) -> Self {
+        PointRegistry {
+            points:RwLock::new(vec![]),
+            loaded:RwLock::new(None),
+            reload_interval:Duration::from_secs(reload_secs),
+        }
+    }
+
+    pub fn reload(&self, conn:&PgConnection) -> Result<usize,Error> {
+        let points = models::sensor_points(conn)?;
+        let count = points.len();
+        *self.points.write().unwrap() = points;
+        *self.loaded.write().unwrap() = Some(Instant::now());
+        Ok(count)
+    }
+
+    fn reload_if_stale(&self, conn:&PgConnection) {
+        let stale = match *self.loaded.read().unwrap() {
+            Some(t) => t.elapsed() >= self.reload_interval,
+            None => true,
+        };
+        if stale {
+            match self.reload(conn) {
+                Ok(count) => println!("Loaded {} sensor points", count),
+                Err(e) => println!("Reload sensor points failed: {}", e.to_string()),
+            }
+        }
+    }
+
+    // the most specific row wins: a device match beats a global row, a slave match beats "any"
+    pub fn lookup(&self, conn:&PgConnection, dev_id:i32, point_id:&str, slave_addr:&str, slave_index:&str) -> Option<PointMapping> {
+        self.reload_if_stale(conn);
+        let points = self.points.read().unwrap();
+        points.iter()
+            .filter(|p| p.point_id == point_id)
+            .filter_map(|p| {
+                let dev_score = match p.device_id {
+                    Some(d) if d == dev_id => 4,
+                    Some(_) => return None,
+                    None => 0,
+                };
+                let addr_score = match p.slave_addr.as_ref() {
+                    Some(a) if a == slave_addr => 2,
+                    Some(_) => return None,
+                    None => 0,
+                };
+                let index_score = match p.slave_index.as_ref() {
+                    Some(i) if i == slave_index => 1,
+                    Some(_) => return None,
+                    None => 0,
+                };
+                Some((dev_score+addr_score+index_score, p))
+            })
+            .max_by_key(|(score, _)| *score)
+            .map(|(_, p)| PointMapping::from(p))
+    }
+}
@@ -0,0 +1,157 @@
+use bigdecimal::ToPrimitive;
+use diesel::pg::PgConnection;
+use std::{sync::RwLock, time::{Duration, Instant}};
+
+use super::error::Error;
+use super::models::{self, QcRule, ReadingTime};
+use super::points::PointKind;
+
+// changes are measured over at least this long, so two readings a second apart don't look like a jump
+const MIN_RATE_SECS:i64 = 60;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Quality {
+    Good,
+    OutOfRange,
+    RateOfChange,
+    FlatLine,
+}
+
+impl Quality {
+    pub fn code(&self) -> i16 {
+        match self {
+            Self::Good => models::QUALITY_GOOD,
+            Self::OutOfRange => 1,
+            Self::RateOfChange => 2,
+            Self::FlatLine => 3,
+        }
+    }
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Good => "good",
+            Self::OutOfRange => "out of range",
+            Self::RateOfChange => "rate of change",
+            Self::FlatLine => "flat line",
+        }
+    }
+}
+
+// qc_rules cache, reloaded like the sensor_points registry
+pub struct QcRegistry {
+    rules:RwLock<Vec<QcRule>>,
+    loaded:RwLock<Option<Instant>>,
+    reload_interval:Duration,
+}
+
+impl QcRegistry {
+    pub fn new(reload_secs:u64) -> Self {
+        QcRegistry {
+            rules:RwLock::new(vec![]),
+            loaded:RwLock::new(None),
+            reload_interval:Duration::from_secs(reload_secs),
+        }
+    }
+
+    pub fn reload(&self, conn:&PgConnection) -> Result<usize,Error> {
+        let rules = models::qc_rules(conn)?;
+        let count = rules.len();
+        *self.rules.write().unwrap() = rules;
+        *self.loaded.write().unwrap() = Some(Instant::now());
+        Ok(count)
+    }
+
+    fn reload_if_stale(&self, conn:&PgConnection) {
+        let stale = match *self.loaded.read().unwrap() {
+            Some(t) => t.elapsed() >= self.reload_interval,
+            None => true,
+        };
+        if stale {
+            match self.reload(conn) {
+                Ok(count) => println!("Loaded {} qc rules", count),
+                Err(e) => println!("Reload qc rules failed: {}", e.to_string()),
+            }
+        }
+    }
+
+    // a device rule replaces the default rule of the same kind
+    fn rule(&self, conn:&PgConnection, dev_id:i32, kind:&str) -> Option<RuleLimits> {
+        self.reload_if_stale(conn);
+        let rules = self.rules.read().unwrap();
+        rules.iter()
+            .filter(|r| r.kind == kind && r.device_id.map(|d| d == dev_id).unwrap_or(true))
+            .max_by_key(|r| r.device_id.is_some())
+            .map(RuleLimits::from)
+    }
+
+    // rain is checked as the increment, depth as the reading itself against earlier readings of its channel
+    pub fn check(&self, conn:&PgConnection, dev_id:i32, kind:&PointKind, channel:&str, value:f32, time:ReadingTime) -> Quality {
+        let kind_name = match kind {
+            PointKind::Rain | PointKind::RainDelta => "rain",
+            PointKind::Depth => "depth",
+            _ => return Quality::Good,
+        };
+        let rule = match self.rule(conn, dev_id, kind_name) {
+            Some(r) => r,
+            None => return Quality::Good,
+        };
+        let quality = match check_reading(conn, dev_id, kind_name, channel, value, time, &rule) {
+            Ok(q) => q,
+            Err(e) => {
+                println!("{}", e.to_string());
+                Quality::Good
+            }
+        };
+        if quality != Quality::Good {
+            println!("Suspect {} {} on device {}: {}", kind_name, value, dev_id, quality.as_str());
+        }
+        quality
+    }
+}
+
+struct RuleLimits {
+    min_value:Option<f32>,
+    max_value:Option<f32>,
+    max_rate:Option<f32>,
+    flatline_secs:Option<i64>,
+}
+
+impl From<&QcRule> for RuleLimits {
+    fn from(rule:&QcRule) -> RuleLimits {
+        RuleLimits {
+            min_value:rule.min_value.as_ref().and_then(|v| v.to_f32()),
+            max_value:rule.max_value.as_ref().and_then(|v| v.to_f32()),
+            max_rate:rule.max_rate.as_ref().and_then(|v| v.to_f32()),
+            flatline_secs:rule.flatline_secs.map(|s| s as i64),
+        }
+    }
+}
+
+fn check_reading(conn:&PgConnection, dev_id:i32, kind:&str, channel:&str, value:f32, time:ReadingTime, rule:&RuleLimits) -> Result<Quality,Error> {
+    if rule.min_value.map(|min| value < min).unwrap_or(false) || rule.max_value.map(|max| value > max).unwrap_or(false) {
+        return Ok(Quality::OutOfRange);
+    }
+    if let Some(max_rate) = rule.max_rate {
+        let previous = match kind {
+            "rain" => models::last_good_rainfall(conn, dev_id, time.observed_time())?.map(|(_, t)| (0.0, t)),
+            _ => models::last_good_depth(conn, dev_id, channel, time.observed_time())?,
+        };
+        // a rain increment is already the change since the previous reading
+        if let Some((last, last_time)) = previous {
+            let secs = (time.observed - last_time.timestamp()).max(MIN_RATE_SECS);
+            let rate = (value - last).abs() * 3600.0 / secs as f32;
+            if rate > max_rate {
+                return Ok(Quality::RateOfChange);
+            }
+        }
+    }
+    // a dry channel reads its minimum for weeks, only a stuck reading above it is a flat line
+    let dry = rule.min_value.map(|min| value <= min).unwrap_or(false);
+    if let (Some(flatline_secs), "depth", false) = (rule.flatline_secs, kind, dry) {
+        if let Some(since) = models::depth_unchanged_since(conn, dev_id, channel, value)? {
+            if time.observed - since.timestamp() >= flatline_secs {
+                return Ok(Quality::FlatLine);
+            }
+        }
+    }
+    Ok(Quality::Good)
+}
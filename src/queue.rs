@@ -0,0 +1,162 @@
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use std::sync::{Arc, Mutex, RwLock, mpsc::{self, SyncSender, TrySendError}};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+use super::error::Error;
+use super::ingest::PayloadFormat;
+
+pub enum Job {
+    Message {
+        topic:String,
+        device_key:String,
+        payload:String,
+        format:PayloadFormat,
+        received:i64,
+    },
+    Will {
+        device_key:String,
+    },
+    Rejected {
+        topic:String,
+        payload:String,
+        reason:String,
+        received:i64,
+    },
+}
+
+impl Job {
+    // jobs with the same key run one after the other
+    pub fn order_key(&self) -> &str {
+        match self {
+            Job::Message { device_key, .. } => device_key,
+            Job::Will { device_key } => device_key,
+            Job::Rejected { topic, .. } => topic,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Backpressure {
+    // wait for room, which holds up the broker connection until the workers catch up
+    Block,
+    // drop the message when its worker's queue is full
+    Drop,
+}
+
+impl Backpressure {
+    pub fn parse(policy:&str) -> Result<Backpressure,Error> {
+        match policy {
+            "block" => Ok(Backpressure::Block),
+            "drop" => Ok(Backpressure::Drop),
+            other => Err(Error::MqttError(format!("unknown queue policy {}", other))),
+        }
+    }
+}
+
+pub struct QueueStats {
+    pub depth:usize,
+    pub max_worker_depth:usize,
+    pub processed:usize,
+    pub dropped:usize,
+}
+
+// one bounded channel per worker; a device always hashes to the same worker, so its jobs stay in order
+pub struct WorkQueue {
+    senders:RwLock<Vec<SyncSender<Job>>>,
+    depths:Arc<Vec<AtomicUsize>>,
+    processed:Arc<AtomicUsize>,
+    dropped:AtomicUsize,
+    policy:Backpressure,
+    handles:Mutex<Vec<JoinHandle<()>>>,
+}
+
+impl WorkQueue {
+    pub fn start<F>(workers:usize,capacity:usize,policy:Backpressure,handler:F) -> WorkQueue
+        where F: Fn(Job) + Send + Sync + 'static {
+        let workers = workers.max(1);
+        let handler = Arc::new(handler);
+        let depths = Arc::new((0..workers).map(|_| AtomicUsize::new(0)).collect::<Vec<_>>());
+        let processed = Arc::new(AtomicUsize::new(0));
+        let mut senders = vec![];
+        let mut handles = vec![];
+        for index in 0..workers {
+            let (sender, receiver) = mpsc::sync_channel::<Job>(capacity.max(1));
+            let handler = handler.clone();
+            let depths = depths.clone();
+            let processed = processed.clone();
+            handles.push(thread::spawn(move || {
+                for job in receiver {
+                    handler(job);
+                    depths[index].fetch_sub(1, Ordering::SeqCst);
+                    processed.fetch_add(1, Ordering::SeqCst);
+                }
+            }));
+            senders.push(sender);
+        }
+        WorkQueue {
+            senders:RwLock::new(senders),
+            depths,
+            processed,
+            dropped:AtomicUsize::new(0),
+            policy,
+            handles:Mutex::new(handles),
+        }
+    }
+
+    fn worker_of(&self,key:&str,workers:usize) -> usize {
+        let mut hasher = DefaultHasher::new();
+        key.hash(&mut hasher);
+        (hasher.finish() % workers as u64) as usize
+    }
+
+    pub fn push(&self,job:Job) -> Result<(),Error> {
+        let senders = self.senders.read().unwrap();
+        if senders.is_empty() {
+            return Err(Error::MqttError("work queue is shut down".to_string()));
+        }
+        let index = self.worker_of(job.order_key(), senders.len());
+        self.depths[index].fetch_add(1, Ordering::SeqCst);
+        let sent = match self.policy {
+            Backpressure::Block => senders[index].send(job).map_err(|_| "worker stopped"),
+            Backpressure::Drop => senders[index].try_send(job).map_err(|e| match e {
+                TrySendError::Full(_) => "queue full",
+                TrySendError::Disconnected(_) => "worker stopped",
+            }),
+        };
+        sent.map_err(|reason| {
+            self.depths[index].fetch_sub(1, Ordering::SeqCst);
+            self.dropped.fetch_add(1, Ordering::SeqCst);
+            Error::MqttError(format!("Error queue message to {}", reason))
+        })
+    }
+
+    pub fn stats(&self) -> QueueStats {
+        let depths:Vec<usize> = self.depths.iter().map(|d| d.load(Ordering::SeqCst)).collect();
+        QueueStats {
+            depth:depths.iter().sum(),
+            max_worker_depth:depths.iter().cloned().max().unwrap_or(0),
+            processed:self.processed.load(Ordering::SeqCst),
+            dropped:self.dropped.load(Ordering::SeqCst),
+        }
+    }
+
+    // stop taking jobs and let the workers drain what is queued
+    pub fn shutdown(&self,timeout:Duration) -> Result<(),Error> {
+        self.senders.write().unwrap().clear();
+        let started = Instant::now();
+        while self.stats().depth > 0 && started.elapsed() < timeout {
+            thread::sleep(Duration::from_millis(100));
+        }
+        let pending = self.stats().depth;
+        if pending > 0 {
+            return Err(Error::MqttError(format!("{} queued messages not stored at shutdown", pending)));
+        }
+        for handle in self.handles.lock().unwrap().drain(..) {
+            handle.join().ok();
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,175 @@
+use bigdecimal::{BigDecimal, ToPrimitive};
+
+use super::error::Error;
+use super::models::{RatingCurveRow, RatingPoint};
+
+// h is the stage above stage_offset in metres, Q in m3/s
+#[derive(Debug, Clone, PartialEq)]
+pub enum RatingCurve {
+    // Q = C*b*h^1.5
+    Rectangular { coefficient:f32, width:f32 },
+    // Q = C*(b*h^1.5 + 0.8*z*h^2.5), z horizontal per vertical of the side walls
+    Trapezoid { coefficient:f32, width:f32, side_slope:f32 },
+    // Q = C*tan(angle/2)*h^2.5
+    Triangular { coefficient:f32, notch_angle:f32 },
+    // Q = a*h^b
+    Power { a:f32, b:f32 },
+    // linear between surveyed (stage, discharge) pairs, on the stage itself
+    Table(Vec<(f32,f32)>),
+}
+
+#[derive(Debug, Clone)]
+pub struct Rating {
+    curve:RatingCurve,
+    stage_offset:f32,
+    min_stage:Option<f32>,
+    max_stage:Option<f32>,
+}
+
+fn number(value:&Option<BigDecimal>) -> Option<f32> {
+    value.as_ref().and_then(|v| v.to_f32())
+}
+
+fn required(value:&Option<BigDecimal>,name:&str,curve_type:&str) -> Result<f32,Error> {
+    number(value).ok_or_else(|| Error::RatingError(format!("{} curve needs {}", curve_type, name)))
+}
+
+impl Rating {
+    pub fn new(row:&RatingCurveRow,points:&[RatingPoint]) -> Result<Rating,Error> {
+        let kind = row.curve_type.as_str();
+        let curve = match kind {
+            "rectangular" => RatingCurve::Rectangular {
+                coefficient:number(&row.coefficient).unwrap_or(1.5),
+                width:required(&row.width, "width", kind)?,
+            },
+            "trapezoid" => RatingCurve::Trapezoid {
+                coefficient:number(&row.coefficient).unwrap_or(1.5),
+                width:required(&row.width, "width", kind)?,
+                side_slope:required(&row.side_slope, "side_slope", kind)?,
+            },
+            "triangular" => RatingCurve::Triangular {
+                coefficient:number(&row.coefficient).unwrap_or(1.4),
+                notch_angle:number(&row.notch_angle).unwrap_or(90.0),
+            },
+            "power" => RatingCurve::Power {
+                a:required(&row.coefficient, "coefficient", kind)?,
+                b:required(&row.exponent, "exponent", kind)?,
+            },
+            "table" => {
+                let mut table:Vec<(f32,f32)> = points.iter()
+                    .filter_map(|p| Some((p.stage.to_f32()?, p.discharge.to_f32()?)))
+                    .collect();
+                table.sort_by(|a, b| a.0.partial_cmp(&b.0).unwrap_or(std::cmp::Ordering::Equal));
+                if table.len() < 2 {
+                    return Err(Error::RatingError("table curve needs at least two rating points".to_string()));
+                }
+                RatingCurve::Table(table)
+            }
+            other => return Err(Error::RatingError(format!("unknown curve type {}", other))),
+        };
+        Ok(Rating {
+            curve,
+            stage_offset:row.stage_offset.to_f32().unwrap_or(0.0),
+            min_stage:number(&row.min_stage),
+            max_stage:number(&row.max_stage),
+        })
+    }
+
+    // the formula used before rating curves, for devices without one
+    pub fn rectangular_weir(width:f32) -> Rating {
+        Rating {
+            curve:RatingCurve::Rectangular { coefficient:1.5, width },
+            stage_offset:0.0,
+            min_stage:None,
+            max_stage:None,
+        }
+    }
+
+    // None outside the validity range, the curve isn't extrapolated
+    pub fn flow(&self,stage:f32) -> Option<f32> {
+        if self.min_stage.map(|min| stage < min).unwrap_or(false) || self.max_stage.map(|max| stage > max).unwrap_or(false) {
+            return None;
+        }
+        let h = (stage - self.stage_offset).max(0.0);
+        let flow = match &self.curve {
+            RatingCurve::Rectangular { coefficient, width } => coefficient*width*h.powf(1.5),
+            RatingCurve::Trapezoid { coefficient, width, side_slope } => coefficient*(width*h.powf(1.5) + 0.8*side_slope*h.powf(2.5)),
+            RatingCurve::Triangular { coefficient, notch_angle } => coefficient*(notch_angle.to_radians()/2.0).tan()*h.powf(2.5),
+            RatingCurve::Power { a, b } => a*h.powf(*b),
+            RatingCurve::Table(table) => return interpolate(table, stage),
+        };
+        Some(flow)
+    }
+}
+
+fn interpolate(table:&[(f32,f32)],stage:f32) -> Option<f32> {
+    let first = table.first()?;
+    let last = table.last()?;
+    if stage < first.0 || stage > last.0 {
+        return None;
+    }
+    table.windows(2)
+        .find(|w| stage <= w[1].0)
+        .map(|w| {
+            let ((s0, q0), (s1, q1)) = (w[0], w[1]);
+            if s1 > s0 {
+                q0 + (q1 - q0)*(stage - s0)/(s1 - s0)
+            } else {
+                q1
+            }
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rating(curve:RatingCurve,stage_offset:f32,min_stage:Option<f32>,max_stage:Option<f32>) -> Rating {
+        Rating { curve, stage_offset, min_stage, max_stage }
+    }
+
+    fn close(a:Option<f32>,b:f32) -> bool {
+        a.map(|a| (a - b).abs() < 1e-4).unwrap_or(false)
+    }
+
+    #[test]
+    fn rectangular_weir_matches_old_formula() {
+        let weir = Rating::rectangular_weir(2.0);
+        assert!(close(weir.flow(0.5), 1.5*2.0*0.5f32.powf(1.5)));
+        assert_eq!(weir.flow(0.0), Some(0.0));
+    }
+
+    #[test]
+    fn stage_below_offset_is_no_flow() {
+        let power = rating(RatingCurve::Power { a:3.0, b:2.0 }, 0.2, None, None);
+        assert_eq!(power.flow(0.1), Some(0.0));
+        assert!(close(power.flow(0.7), 3.0*0.25));
+    }
+
+    #[test]
+    fn outside_validity_range_is_none() {
+        let curve = RatingCurve::Triangular { coefficient:1.4, notch_angle:90.0 };
+        let notch = rating(curve, 0.0, Some(0.05), Some(0.5));
+        assert_eq!(notch.flow(0.01), None);
+        assert_eq!(notch.flow(0.6), None);
+        assert!(close(notch.flow(0.25), 1.4*0.25f32.powf(2.5)));
+    }
+
+    #[test]
+    fn table_interpolates_between_points() {
+        let table = rating(RatingCurve::Table(vec![(0.0, 0.0), (1.0, 10.0), (2.0, 30.0)]), 0.0, None, None);
+        assert!(close(table.flow(0.5), 5.0));
+        assert!(close(table.flow(1.0), 10.0));
+        assert!(close(table.flow(1.5), 20.0));
+        assert!(close(table.flow(2.0), 30.0));
+        // the table isn't extrapolated
+        assert_eq!(table.flow(-0.1), None);
+        assert_eq!(table.flow(2.5), None);
+    }
+
+    #[test]
+    fn table_with_repeated_stage() {
+        let table = rating(RatingCurve::Table(vec![(0.0, 0.0), (1.0, 10.0), (1.0, 12.0)]), 0.0, None, None);
+        assert!(close(table.flow(1.0), 10.0));
+    }
+}
@@ -0,0 +1,222 @@
+use chrono::prelude::*;
+use csv;
+use serde_derive::Deserialize;
+use serde_json;
+use std::fs::{File, OpenOptions};
+use std::io::{BufRead, BufReader, Write};
+
+use super::config::{BrokerConfig, Config};
+use super::error::Error;
+use super::ingest::{DeviceTime, Ingestor, PayloadData, PayloadFormat};
+use super::models::{self, PgPool};
+use super::topic::TopicTemplate;
+
+// a raw message from a vendor dump, time is when the broker received it
+pub struct RawRecord {
+    pub topic:String,
+    pub payload:String,
+    pub time:i64,
+}
+
+// JSON Lines: {"topic": "...", "payload": {...} or "...", "time": 1622505600 or "2021-06-01 08:00:00"}
+#[derive(Deserialize)]
+struct JsonRecord {
+    topic:String,
+    payload:serde_json::Value,
+    time:DeviceTime,
+}
+
+// adds one record to a JSON Lines file, creating it if needed
+pub fn append_jsonl(path:&str,record:&RawRecord) -> Result<(),Error> {
+    let line = serde_json::json!({
+        "topic":record.topic,
+        "payload":record.payload,
+        "time":record.time,
+    });
+    OpenOptions::new().create(true).append(true).open(path)
+        .and_then(|mut file| writeln!(file, "{}", line))
+        .map_err(|e| Error::PayloadError(format!("Error write {} to {}", path, e)))
+}
+
+// CSV with a topic,payload,time header
+#[derive(Deserialize)]
+struct CsvRecord {
+    topic:String,
+    payload:String,
+    time:String,
+}
+
+pub fn read_jsonl(path:&str) -> Result<Vec<RawRecord>,Error> {
+    let file = File::open(path)
+        .map_err(|e| Error::PayloadError(format!("Error open {} to {}", path, e)))?;
+    let mut records = vec![];
+    for (i, line) in BufReader::new(file).lines().enumerate() {
+        let line = line.map_err(|e| Error::PayloadError(format!("Error read {} to {}", path, e)))?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let record = serde_json::from_str::<JsonRecord>(&line).ok().and_then(|JsonRecord { topic, payload, time }| {
+            let payload = match payload {
+                serde_json::Value::String(p) => p,
+                other => other.to_string(),
+            };
+            time.timestamp().map(|time| RawRecord { topic, payload, time })
+        });
+        match record {
+            Some(r) => records.push(r),
+            None => println!("{} line {}: not a raw message, skipped", path, i+1),
+        }
+    }
+    Ok(records)
+}
+
+pub fn read_csv(path:&str) -> Result<Vec<RawRecord>,Error> {
+    let mut reader = csv::Reader::from_path(path)
+        .map_err(|e| Error::PayloadError(format!("Error open {} to {}", path, e)))?;
+    let mut records = vec![];
+    for (i, row) in reader.deserialize::<CsvRecord>().enumerate() {
+        let record = row.ok().and_then(|CsvRecord { topic, payload, time }| {
+            DeviceTime::Text(time).timestamp().map(|time| RawRecord { topic, payload, time })
+        });
+        match record {
+            Some(r) => records.push(r),
+            None => println!("{} row {}: not a raw message, skipped", path, i+1),
+        }
+    }
+    Ok(records)
+}
+
+// files ending in .csv are CSV, everything else JSON Lines
+pub fn read_records(path:&str) -> Result<Vec<RawRecord>,Error> {
+    if path.to_lowercase().ends_with(".csv") {
+        read_csv(path)
+    } else {
+        read_jsonl(path)
+    }
+}
+
+// "%Y-%m-%d" or "%Y-%m-%d %H:%M:%S" in UTC+8, a bare date ends at the end of that day
+pub fn parse_bound(text:&str,end:bool) -> Result<i64,Error> {
+    let local = match NaiveDateTime::parse_from_str(text, "%Y-%m-%d %H:%M:%S") {
+        Ok(t) => t,
+        Err(_) => {
+            let date = NaiveDate::parse_from_str(text, "%Y-%m-%d")
+                .map_err(|_| Error::PayloadError(format!("Error date {}", text)))?;
+            if end {
+                date.and_hms(23, 59, 59)
+            } else {
+                date.and_hms(0, 0, 0)
+            }
+        }
+    };
+    Ok(local.timestamp()-8*3600)
+}
+
+pub struct ReplayOptions {
+    pub dry_run:bool,
+    pub from:Option<i64>,
+    pub to:Option<i64>,
+    pub broker:Option<String>,
+}
+
+#[derive(Debug, Default)]
+pub struct ReplaySummary {
+    pub read:usize,
+    pub out_of_range:usize,
+    pub unmatched:usize,
+    pub rejected:usize,
+    pub messages:usize,
+    // dry run: data points parsed, nothing is stored
+    pub points:usize,
+    pub stored:usize,
+    pub duplicates:usize,
+    // live readings after a replayed range whose increment was taken again
+    pub rebased:usize,
+    // devices whose calculations were rebuilt over the replayed range
+    pub recomputed:usize,
+    pub calculations:usize,
+}
+
+fn replay_broker(config:&Config,name:Option<&str>) -> Result<BrokerConfig,Error> {
+    let brokers = config.brokers();
+    match name {
+        Some(n) => brokers.into_iter().find(|b| b.name() == n)
+            .ok_or_else(|| Error::PayloadError(format!("no broker {} in Config.toml", n))),
+        None => Ok(brokers.into_iter().next().unwrap()),
+    }
+}
+
+// runs the records through the same parsing and storage as live messages, oldest first,
+// with the original receive times, then rebuilds the calculations of every device that got
+// rainfall over the replayed range; a dry run only parses and resolves devices
+pub fn replay(pool:&PgPool,config:&Config,mut records:Vec<RawRecord>,options:&ReplayOptions) -> Result<ReplaySummary,Error> {
+    let broker = replay_broker(config, options.broker.as_deref())?;
+    let format = PayloadFormat::parse(broker.payload_format())?;
+    let templates = broker.topics().into_iter()
+        .map(TopicTemplate::parse)
+        .collect::<Result<Vec<_>,Error>>()?;
+    let ingestor = Ingestor::new(config).replay();
+    let conn = pool.get().map_err(|e| Error::DatabaseError(e.to_string()))?;
+    let mut summary = ReplaySummary::default();
+    summary.read = records.len();
+    records.sort_by_key(|r| r.time);
+    for record in records {
+        if options.from.map(|f| record.time < f).unwrap_or(false) || options.to.map(|t| record.time > t).unwrap_or(false) {
+            summary.out_of_range += 1;
+            continue;
+        }
+        let device_key = match templates.iter().find_map(|t| t.device_key(&record.topic)) {
+            Some(k) => k,
+            None => {
+                println!("Topic {} matches no template, skipped", record.topic);
+                summary.unmatched += 1;
+                continue;
+            }
+        };
+        summary.messages += 1;
+        if options.dry_run {
+            let checked = PayloadData::parse_format(&record.payload, format)
+                .and_then(|p| models::get_id_by_deviceid(&conn, device_key.to_string()).map(|_| p.data_points().len()));
+            match checked {
+                Ok(points) => summary.points += points,
+                Err(e) => {
+                    println!("{} at {}: {}", record.topic, record.time, e.to_string());
+                    summary.rejected += 1;
+                }
+            }
+            continue;
+        }
+        match ingestor.ingest(&conn, &record.topic, device_key, &record.payload, format, record.time) {
+            Ok(report) => {
+                summary.stored += report.stored;
+                summary.duplicates += report.duplicates;
+            }
+            Err(_) => summary.rejected += 1,
+        }
+    }
+    for (dev_id, from, to) in ingestor.replayed() {
+        // the recompute runs up to the rebased live reading, so its calculation follows too
+        let to = match models::rebase_next_rainfall(&conn, dev_id, to, config.rain_counter_max()) {
+            Ok(Some(next)) => {
+                summary.rebased += 1;
+                next
+            }
+            Ok(None) => to,
+            Err(e) => {
+                println!("Rebase device {} failed, calculations not recomputed: {}", dev_id, e.to_string());
+                continue;
+            }
+        };
+        match models::recompute_calculations(&conn, dev_id, from, to, false) {
+            Ok(report) => {
+                summary.recomputed += 1;
+                summary.calculations += report.inserted;
+                if report.later_rows > 0 {
+                    println!("Device {}: {} later calculations kept, recompute them to carry the replay forward", dev_id, report.later_rows);
+                }
+            }
+            Err(e) => println!("Recompute device {} failed: {}", dev_id, e.to_string()),
+        }
+    }
+    Ok(summary)
+}
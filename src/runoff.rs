@@ -0,0 +1,253 @@
+use super::error::Error;
+use super::models::RunoffParams;
+
+// SCS-CN: dry spell after which the next rain starts a new event
+const SCS_EVENT_GAP_HOURS:f32 = 6.0;
+// SCS-CN and rational: rain of closer readings is spread over this many seconds,
+// so a short gap doesn't turn into a discharge spike
+const MIN_RATE_INTERVAL:f32 = 300.0;
+
+// kept in the storage and wi columns; what they hold depends on the model
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RunoffState {
+    pub storage:f32,
+    pub wi:f32,
+}
+
+pub trait RunoffModel {
+    // stored with every calculation and model state
+    fn name(&self) -> &'static str;
+    // the state of a device that has none yet
+    fn init_state(&self) -> RunoffState;
+    // rain in mm fallen over interval seconds; the next state and the discharge in m3/s
+    fn step(&self,state:RunoffState,rain:f32,interval:f32) -> (RunoffState,f32);
+}
+
+// model_parameters.runoff_model: "reservoir", "scs" or "rational"
+pub fn runoff_model(params:&RunoffParams) -> Result<Box<dyn RunoffModel>,Error> {
+    match params.runoff_model.as_str() {
+        "reservoir" => Ok(Box::new(Reservoir { params:params.clone() })),
+        "scs" => {
+            if params.curve_number <= 0.0 || params.curve_number > 100.0 {
+                return Err(Error::ModelError(format!("curve number {} out of range", params.curve_number)));
+            }
+            Ok(Box::new(ScsCurveNumber {
+                curve_number:params.curve_number,
+                catchment_area:params.catchment_area,
+            }))
+        }
+        "rational" => {
+            if params.runoff_coefficient < 0.0 || params.runoff_coefficient > 1.0 {
+                return Err(Error::ModelError(format!("runoff coefficient {} out of range", params.runoff_coefficient)));
+            }
+            Ok(Box::new(Rational {
+                coefficient:params.runoff_coefficient,
+                catchment_area:params.catchment_area,
+            }))
+        }
+        other => Err(Error::ModelError(format!("unknown runoff model {}", other))),
+    }
+}
+
+// soil storage with a loss rate feeding a nonlinear reservoir q = a*w^2 + b*w;
+// storage is the deficit (negative) or excess of the soil, wi the reservoir state
+pub struct Reservoir {
+    params:RunoffParams,
+}
+
+impl RunoffModel for Reservoir {
+    fn name(&self) -> &'static str {
+        "reservoir"
+    }
+
+    fn init_state(&self) -> RunoffState {
+        RunoffState {
+            storage:-self.params.storage_capacity,
+            wi:0.0,
+        }
+    }
+
+    fn step(&self,state:RunoffState,rain:f32,interval:f32) -> (RunoffState,f32) {
+        let (i_j,s_i) = cal_storage(&self.params, state.storage, rain, interval);
+        let (qu,w_i) = cal_quantity(&self.params, i_j, state.wi, interval);
+        (RunoffState { storage:s_i, wi:w_i }, qu)
+    }
+}
+
+// I净，s_i
+fn cal_storage(params:&RunoffParams,si_1:f32,rain:f32,interval:f32) -> (f32,f32){
+    let si = si_1+rain - params.loss_rate*interval/24.0/12.0/300.0;
+    let si = si.max(-params.storage_capacity);
+    if si > 0.0 {
+        (si,0.0)
+    } else {
+        (0.0,si)
+    }
+}
+
+// quantity,wi
+fn cal_quantity(params:&RunoffParams,i_j:f32,wi_1:f32,interval:f32) -> (f32,f32) {
+    let f= params.catchment_area;let a = params.reservoir_a;let b = params.reservoir_b;
+    let iil = i_j*f*3.333;
+    let d = b/(2.0*a);
+    let g = (b*b+4.0*a*iil).powf(0.5)/(2.0*a);
+    let k = (wi_1 +d - g)/(wi_1+d+g)*(-2.0*g*interval/10000.0).exp();
+    let wi = (1.0+k)/(1.0-k)*g -d;
+    let qi = a*wi*wi+b*wi;
+    (qi,wi)
+}
+
+// SCS curve number: Q = (P-Ia)^2/(P-Ia+S), S = 25400/CN-254, Ia = 0.2S, on the event's
+// accumulated rain; storage is the event rain P in mm, wi the hours since it last rained
+pub struct ScsCurveNumber {
+    curve_number:f32,
+    catchment_area:f32,
+}
+
+impl ScsCurveNumber {
+    fn runoff_depth(&self,rain:f32) -> f32 {
+        let s = 25400.0/self.curve_number - 254.0;
+        let ia = 0.2*s;
+        if rain <= ia {
+            0.0
+        } else {
+            (rain-ia).powi(2)/(rain-ia+s)
+        }
+    }
+}
+
+impl RunoffModel for ScsCurveNumber {
+    fn name(&self) -> &'static str {
+        "scs"
+    }
+
+    fn init_state(&self) -> RunoffState {
+        RunoffState {
+            storage:0.0,
+            wi:SCS_EVENT_GAP_HOURS,
+        }
+    }
+
+    fn step(&self,state:RunoffState,rain:f32,interval:f32) -> (RunoffState,f32) {
+        if rain <= 0.0 {
+            let dry_hours = (state.wi + interval/3600.0).min(9999.0);
+            return (RunoffState { storage:state.storage, wi:dry_hours }, 0.0);
+        }
+        let event_rain = if state.wi >= SCS_EVENT_GAP_HOURS { 0.0 } else { state.storage };
+        let excess = self.runoff_depth(event_rain+rain) - self.runoff_depth(event_rain);
+        // 1 mm over 1 km2 is 1000 m3
+        let quantity = excess*self.catchment_area*1000.0/interval.max(MIN_RATE_INTERVAL);
+        (RunoffState { storage:event_rain+rain, wi:0.0 }, quantity)
+    }
+}
+
+// rational method Q = C*i*A/3.6 with i in mm/h and A in km2; no state
+pub struct Rational {
+    coefficient:f32,
+    catchment_area:f32,
+}
+
+impl RunoffModel for Rational {
+    fn name(&self) -> &'static str {
+        "rational"
+    }
+
+    fn init_state(&self) -> RunoffState {
+        RunoffState {
+            storage:0.0,
+            wi:0.0,
+        }
+    }
+
+    fn step(&self,state:RunoffState,rain:f32,interval:f32) -> (RunoffState,f32) {
+        let intensity = rain*3600.0/interval.max(MIN_RATE_INTERVAL);
+        (state, self.coefficient*intensity*self.catchment_area/3.6)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn params(model:&str) -> RunoffParams {
+        RunoffParams {
+            runoff_model:model.to_string(),
+            ..RunoffParams::default()
+        }
+    }
+
+    fn close(a:f32,b:f32) -> bool {
+        (a - b).abs() <= 1e-3*b.abs().max(1.0)
+    }
+
+    // the values new_calculation produced with its hard-coded constants before model_parameters
+    #[test]
+    fn reservoir_matches_baseline() {
+        let model = runoff_model(&params("reservoir")).unwrap();
+        let state = model.init_state();
+        assert_eq!(state, RunoffState { storage:-30.0, wi:0.0 });
+        let (state, qu) = model.step(state, 10.0, 300.0);
+        assert!(close(state.storage, -20.0104) && state.wi == 0.0 && qu == 0.0);
+        let (state, qu) = model.step(state, 25.0, 300.0);
+        assert!(close(state.storage, 0.0));
+        assert!(close(state.wi, 844.962), "wi {}", state.wi);
+        assert!(close(qu, 912.756), "quantity {}", qu);
+        let (state, qu) = model.step(state, 0.0, 300.0);
+        assert!(close(state.storage, -0.010417) && qu.abs() < 1e-3);
+    }
+
+    #[test]
+    fn scs_accumulates_event_rain() {
+        let model = runoff_model(&params("scs")).unwrap();
+        let state = model.init_state();
+        // below the initial abstraction nothing runs off
+        let (state, qu) = model.step(state, 10.0, 300.0);
+        assert_eq!(state, RunoffState { storage:10.0, wi:0.0 });
+        assert_eq!(qu, 0.0);
+        let (state, qu) = model.step(state, 20.0, 300.0);
+        assert_eq!(state.storage, 30.0);
+        assert!(close(qu, 320.279), "quantity {}", qu);
+    }
+
+    #[test]
+    fn scs_starts_new_event_after_dry_spell() {
+        let model = runoff_model(&params("scs")).unwrap();
+        let (state, _) = model.step(model.init_state(), 30.0, 300.0);
+        let (state, qu) = model.step(state, 0.0, 3600.0*SCS_EVENT_GAP_HOURS);
+        assert_eq!(qu, 0.0);
+        assert_eq!(state.wi, SCS_EVENT_GAP_HOURS);
+        let (state, qu) = model.step(state, 10.0, 300.0);
+        assert_eq!(state.storage, 10.0);
+        assert_eq!(qu, 0.0);
+    }
+
+    #[test]
+    fn rational_uses_intensity() {
+        let model = runoff_model(&params("rational")).unwrap();
+        let state = model.init_state();
+        // 5 mm in 5 minutes is 60 mm/h
+        let (next, qu) = model.step(state, 5.0, 300.0);
+        assert_eq!(next, state);
+        assert!(close(qu, 0.5*60.0*55.0/3.6));
+        let (_, qu) = model.step(state, 5.0, 600.0);
+        assert!(close(qu, 0.5*30.0*55.0/3.6));
+    }
+
+    #[test]
+    fn short_intervals_are_clamped() {
+        for name in ["scs", "rational"].iter() {
+            let model = runoff_model(&params(name)).unwrap();
+            let (state, _) = model.step(model.init_state(), 20.0, 300.0);
+            let (_, normal) = model.step(state, 10.0, 300.0);
+            let (_, short) = model.step(state, 10.0, 5.0);
+            assert_eq!(normal, short, "{}", name);
+        }
+    }
+
+    #[test]
+    fn rejects_bad_parameters() {
+        assert!(runoff_model(&RunoffParams { curve_number:0.0, ..params("scs") }).is_err());
+        assert!(runoff_model(&RunoffParams { runoff_coefficient:1.5, ..params("rational") }).is_err());
+        assert!(runoff_model(&params("unit_hydrograph")).is_err());
+    }
+}
@@ -11,6 +11,44 @@ table! {
         one_half_hour -> Int8,
         two_hour -> Int8,
         three_hour -> Int8,
+        receive_time -> Timestamptz,
+        quality -> Int2,
+        model -> Varchar,
+    }
+}
+
+table! {
+    dead_letters (id) {
+        id -> Int4,
+        topic -> Varchar,
+        payload -> Text,
+        reason -> Varchar,
+        receive_time -> Timestamptz,
+    }
+}
+
+table! {
+    device_health (id) {
+        id -> Int8,
+        device_id -> Int4,
+        metric -> Varchar,
+        value -> Numeric,
+        alarm -> Bool,
+        create_time -> Timestamptz,
+        half_hour -> Int8,
+        receive_time -> Timestamptz,
+    }
+}
+
+table! {
+    device_status (device_id) {
+        device_id -> Int4,
+        online -> Bool,
+        last_seen -> Timestamptz,
+        last_payload_size -> Nullable<Int4>,
+        last_values -> Text,
+        offline_reason -> Nullable<Varchar>,
+        status_time -> Timestamptz,
     }
 }
 
@@ -28,6 +66,90 @@ table! {
         three_design -> Numeric,
         stream_width -> Nullable<Numeric>,
         rainfall_area -> Nullable<Numeric>,
+        sl651_address -> Nullable<Varchar>,
+        offline_after -> Nullable<Int4>,
+        primary_channel -> Nullable<Varchar>,
+        broker -> Nullable<Varchar>,
+    }
+}
+
+table! {
+    health_thresholds (id) {
+        id -> Int4,
+        device_id -> Nullable<Int4>,
+        metric -> Varchar,
+        min_value -> Nullable<Numeric>,
+        max_value -> Nullable<Numeric>,
+    }
+}
+
+table! {
+    message_fingerprints (device_id, fingerprint) {
+        device_id -> Int4,
+        fingerprint -> Varchar,
+        receive_time -> Timestamptz,
+    }
+}
+
+table! {
+    model_checkpoints (id) {
+        id -> Int8,
+        device_id -> Int4,
+        storage -> Numeric,
+        wi -> Numeric,
+        state_time -> Timestamptz,
+        calculation_id -> Nullable<Int4>,
+        reason -> Varchar,
+        restored_from -> Nullable<Int8>,
+        create_time -> Timestamptz,
+        model -> Varchar,
+    }
+}
+
+table! {
+    model_parameters (device_id) {
+        device_id -> Int4,
+        catchment_area -> Nullable<Numeric>,
+        storage_capacity -> Numeric,
+        loss_rate -> Numeric,
+        reservoir_a -> Numeric,
+        reservoir_b -> Numeric,
+        update_time -> Timestamptz,
+        runoff_model -> Varchar,
+        curve_number -> Numeric,
+        runoff_coefficient -> Numeric,
+    }
+}
+
+table! {
+    model_state (device_id) {
+        device_id -> Int4,
+        storage -> Numeric,
+        wi -> Numeric,
+        state_time -> Timestamptz,
+        calculation_id -> Nullable<Int4>,
+        update_time -> Timestamptz,
+        model -> Varchar,
+    }
+}
+
+table! {
+    qc_rules (id) {
+        id -> Int4,
+        device_id -> Nullable<Int4>,
+        kind -> Varchar,
+        min_value -> Nullable<Numeric>,
+        max_value -> Nullable<Numeric>,
+        max_rate -> Nullable<Numeric>,
+        flatline_secs -> Nullable<Int4>,
+    }
+}
+
+table! {
+    rain_counters (device_id) {
+        device_id -> Int4,
+        counter -> Numeric,
+        update_time -> Timestamptz,
     }
 }
 
@@ -42,6 +164,58 @@ table! {
         one_half_hour -> Int8,
         two_hour -> Int8,
         three_hour -> Int8,
+        counter -> Nullable<Numeric>,
+        counter_event -> Varchar,
+        receive_time -> Timestamptz,
+        quality -> Int2,
+    }
+}
+
+table! {
+    rating_curves (id) {
+        id -> Int4,
+        device_id -> Int4,
+        curve_type -> Varchar,
+        coefficient -> Nullable<Numeric>,
+        exponent -> Nullable<Numeric>,
+        width -> Nullable<Numeric>,
+        side_slope -> Nullable<Numeric>,
+        notch_angle -> Nullable<Numeric>,
+        stage_offset -> Numeric,
+        min_stage -> Nullable<Numeric>,
+        max_stage -> Nullable<Numeric>,
+    }
+}
+
+table! {
+    rating_points (id) {
+        id -> Int4,
+        curve_id -> Int4,
+        stage -> Numeric,
+        discharge -> Numeric,
+    }
+}
+
+table! {
+    raw_messages (id) {
+        id -> Int8,
+        topic -> Varchar,
+        payload -> Text,
+        receive_time -> Timestamptz,
+    }
+}
+
+table! {
+    sensor_points (id) {
+        id -> Int4,
+        device_id -> Nullable<Int4>,
+        point_id -> Varchar,
+        slave_addr -> Nullable<Varchar>,
+        slave_index -> Nullable<Varchar>,
+        kind -> Varchar,
+        scale -> Numeric,
+        value_offset -> Numeric,
+        channel -> Nullable<Varchar>,
     }
 }
 
@@ -57,16 +231,45 @@ table! {
         one_half_hour -> Nullable<Int8>,
         two_hour -> Nullable<Int8>,
         three_hour -> Nullable<Int8>,
+        receive_time -> Timestamptz,
+        quality -> Int2,
+        channel -> Varchar,
     }
 }
 
 joinable!(calculations -> devices (device_id));
+joinable!(device_health -> devices (device_id));
+joinable!(device_status -> devices (device_id));
+joinable!(health_thresholds -> devices (device_id));
+joinable!(message_fingerprints -> devices (device_id));
+joinable!(model_checkpoints -> devices (device_id));
+joinable!(model_parameters -> devices (device_id));
+joinable!(model_state -> devices (device_id));
+joinable!(qc_rules -> devices (device_id));
+joinable!(rain_counters -> devices (device_id));
 joinable!(rainfalls -> devices (device_id));
+joinable!(rating_curves -> devices (device_id));
+joinable!(rating_points -> rating_curves (curve_id));
+joinable!(sensor_points -> devices (device_id));
 joinable!(water_depths -> devices (device_id));
 
 allow_tables_to_appear_in_same_query!(
     calculations,
+    dead_letters,
+    device_health,
+    device_status,
     devices,
+    health_thresholds,
+    message_fingerprints,
+    model_checkpoints,
+    model_parameters,
+    model_state,
+    qc_rules,
+    rain_counters,
     rainfalls,
+    rating_curves,
+    rating_points,
+    raw_messages,
+    sensor_points,
     water_depths,
 );
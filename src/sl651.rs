@@ -0,0 +1,706 @@
+use chrono::prelude::*;
+use std::io::{Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::Arc;
+use std::{str, thread, time::Duration};
+
+use super::config::Config;
+use super::error::Error;
+use super::ingest::{self, DataPoint, Ingestor};
+use super::models::{self, PgPool};
+
+// SL651-2014 hydrological telemetry frames, HEX/BCD and ASCII encodings
+const SOH_HEX:[u8;2] = [0x7E, 0x7E];
+const SOH_ASCII:u8 = 0x01;
+const STX:u8 = 0x02;
+const SYN:u8 = 0x16;
+const ETX:u8 = 0x03;
+const ETB:u8 = 0x17;
+const EOT:u8 = 0x04;
+
+pub const FN_KEEPALIVE:u8 = 0x2F;
+pub const FN_TEST:u8 = 0x30;
+pub const FN_TIMED:u8 = 0x32;
+pub const FN_ADDED:u8 = 0x33;
+pub const FN_HOURLY:u8 = 0x34;
+
+// header bytes before the body, and trailer bytes (end flag + crc) after it
+const HEX_HEADER:usize = 14;
+const HEX_TRAILER:usize = 3;
+const ASCII_HEADER:usize = 24;
+const ASCII_TRAILER:usize = 5;
+
+const READ_TIMEOUT_SECS:u64 = 300;
+// longer than any frame, a connection buffering more is sending garbage
+const MAX_BUFFER:usize = 8192;
+
+const ELEMENT_NAMES:[(u8, &str);9] = [
+    (0x1A, "P1"), (0x1F, "PD"), (0x20, "PJ"), (0x26, "PT"), (0x27, "Q"),
+    (0x38, "VT"), (0x39, "Z"), (0x3A, "ZB"), (0x3B, "ZU"),
+];
+
+#[derive(Debug)]
+pub struct Frame {
+    pub ascii:bool,
+    pub center:u8,
+    pub station:String,
+    pub password:u16,
+    pub function:u8,
+    pub body:Vec<u8>,
+}
+
+#[derive(Debug)]
+pub struct Element {
+    pub name:String,
+    pub value:f32,
+    pub time:Option<i64>,
+}
+
+#[derive(Debug)]
+pub struct Report {
+    pub serial:u16,
+    pub send_time:Option<i64>,
+    pub elements:Vec<Element>,
+}
+
+fn crc16(data:&[u8]) -> u16 {
+    let mut crc = 0xFFFFu16;
+    for b in data {
+        crc ^= *b as u16;
+        for _ in 0..8 {
+            if crc & 1 == 1 {
+                crc = (crc >> 1) ^ 0xA001;
+            } else {
+                crc >>= 1;
+            }
+        }
+    }
+    crc
+}
+
+pub fn to_hex(bytes:&[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02X}", b)).collect()
+}
+
+fn from_hex(text:&str) -> Option<Vec<u8>> {
+    if text.len() % 2 != 0 {
+        return None;
+    }
+    (0..text.len()).step_by(2)
+        .map(|i| text.get(i..i+2).and_then(|h| u8::from_str_radix(h, 16).ok()))
+        .collect()
+}
+
+fn hex_field<T>(raw:&[u8],parse:fn(&str,u32) -> Result<T,std::num::ParseIntError>) -> Result<T,Error> {
+    str::from_utf8(raw).ok()
+        .and_then(|t| parse(t, 16).ok())
+        .ok_or_else(|| Error::PayloadError(format!("Error parse sl651 field {}", to_hex(raw))))
+}
+
+fn bcd(b:u8) -> Option<u32> {
+    let (high, low) = (b >> 4, b & 0x0F);
+    if high > 9 || low > 9 {
+        return None;
+    }
+    Some((high*10+low) as u32)
+}
+
+fn to_bcd(v:u32) -> u8 {
+    ((((v/10)%10) << 4) | (v%10)) as u8
+}
+
+// station clocks run on Beijing time
+fn local_stamp(parts:&[u32]) -> Option<i64> {
+    let second = parts.get(5).cloned().unwrap_or(0);
+    let local = NaiveDate::from_ymd_opt(2000+*parts.first()? as i32, *parts.get(1)?, *parts.get(2)?)?
+        .and_hms_opt(*parts.get(3)?, *parts.get(4)?, second)?;
+    Some(local.timestamp()-8*3600)
+}
+
+fn bcd_time(bytes:&[u8]) -> Option<i64> {
+    let parts:Option<Vec<u32>> = bytes.iter().map(|b| bcd(*b)).collect();
+    local_stamp(&parts?)
+}
+
+fn ascii_time(text:&str) -> Option<i64> {
+    let parts:Option<Vec<u32>> = (0..text.len()).step_by(2)
+        .map(|i| text.get(i..i+2).and_then(|d| d.parse().ok()))
+        .collect();
+    local_stamp(&parts?)
+}
+
+// all 0xFF marks a missing value, a leading 0xFF marks a negative one
+fn bcd_value(bytes:&[u8],decimals:u8) -> Option<f32> {
+    if bytes.is_empty() || bytes.iter().all(|b| *b == 0xFF) {
+        return None;
+    }
+    let (sign, digits) = if bytes[0] == 0xFF { (-1.0, &bytes[1..]) } else { (1.0, bytes) };
+    let mut v = 0u64;
+    for b in digits {
+        v = v*100 + bcd(*b)? as u64;
+    }
+    Some(sign*(v as f64/10f64.powi(decimals as i32)) as f32)
+}
+
+fn element_name(id:u8) -> String {
+    ELEMENT_NAMES.iter()
+        .find(|(e, _)| *e == id)
+        .map(|(_, name)| name.to_string())
+        .unwrap_or_else(|| format!("{:02X}", id))
+}
+
+// twelve 5-minute values covering the hour before the observation time
+fn five_minute_elements(name:&str,values:Vec<Option<f32>>,observed:Option<i64>) -> Vec<Element> {
+    values.into_iter().enumerate()
+        .filter_map(|(k, v)| v.map(|value| Element {
+            name:name.to_string(),
+            value,
+            time:observed.map(|t| t-3600+300*(k as i64+1)),
+        }))
+        .collect()
+}
+
+enum FrameLen {
+    // the header hasn't fully arrived
+    Incomplete,
+    // the length field isn't a length, so the start byte wasn't a frame start
+    Invalid,
+    Total(usize),
+}
+
+// total length of the frame at the start of buf
+fn frame_len(buf:&[u8]) -> FrameLen {
+    if buf.starts_with(&SOH_HEX) {
+        match (buf.get(11), buf.get(12)) {
+            (Some(high), Some(low)) => FrameLen::Total(HEX_HEADER+(((*high as usize & 0x0F) << 8) | *low as usize)+HEX_TRAILER),
+            _ => FrameLen::Incomplete,
+        }
+    } else if buf.first() == Some(&SOH_ASCII) {
+        let field = match buf.get(20..23) {
+            Some(f) => f,
+            None => return FrameLen::Incomplete,
+        };
+        match str::from_utf8(field).ok().and_then(|l| usize::from_str_radix(l, 16).ok()) {
+            Some(len) => FrameLen::Total(ASCII_HEADER+len+ASCII_TRAILER),
+            None => FrameLen::Invalid,
+        }
+    } else {
+        FrameLen::Invalid
+    }
+}
+
+// the end flag sits before the crc: two bytes in HEX frames, four hex digits in ASCII ones
+fn end_flag_ok(frame:&[u8]) -> bool {
+    let trailer = if frame.starts_with(&SOH_HEX) { HEX_TRAILER } else { ASCII_TRAILER };
+    frame.len() >= trailer && (frame[frame.len()-trailer] == ETX || frame[frame.len()-trailer] == ETB)
+}
+
+// removes one complete frame from the front of buf, skipping leading garbage; a start byte
+// whose length field or end flag doesn't check out is dropped and the search starts over
+pub fn take_frame(buf:&mut Vec<u8>) -> Option<Vec<u8>> {
+    loop {
+        let start = match (0..buf.len()).find(|i| buf[*i..].starts_with(&SOH_HEX) || buf[*i] == SOH_ASCII) {
+            Some(s) => s,
+            None => {
+                // a trailing 0x7E may be the first half of the next start flag
+                let keep = if buf.last() == Some(&SOH_HEX[0]) { 1 } else { 0 };
+                let garbage = buf.len()-keep;
+                buf.drain(..garbage);
+                return None;
+            }
+        };
+        buf.drain(..start);
+        let len = match frame_len(buf) {
+            FrameLen::Incomplete => return None,
+            FrameLen::Invalid => {
+                buf.drain(..1);
+                continue;
+            }
+            FrameLen::Total(len) => len,
+        };
+        if buf.len() < len {
+            return None;
+        }
+        if !end_flag_ok(&buf[..len]) {
+            buf.drain(..1);
+            continue;
+        }
+        return Some(buf.drain(..len).collect());
+    }
+}
+
+impl Frame {
+    pub fn decode(raw:&[u8]) -> Result<Frame,Error> {
+        if raw.starts_with(&SOH_HEX) {
+            Frame::decode_hex(raw)
+        } else if raw.first() == Some(&SOH_ASCII) {
+            Frame::decode_ascii(raw)
+        } else {
+            Err(Error::PayloadError("Error sl651 frame start".to_string()))
+        }
+    }
+
+    fn decode_hex(raw:&[u8]) -> Result<Frame,Error> {
+        let total = raw.len();
+        if total < HEX_HEADER+HEX_TRAILER {
+            return Err(Error::PayloadError("Error sl651 frame too short".to_string()));
+        }
+        let crc = u16::from_be_bytes([raw[total-2], raw[total-1]]);
+        if crc != crc16(&raw[..total-2]) {
+            return Err(Error::PayloadError(format!("Error sl651 crc {:04X}", crc)));
+        }
+        if raw[11] >> 4 != 0 {
+            return Err(Error::PayloadError("Error sl651 downlink frame".to_string()));
+        }
+        if raw[13] != STX {
+            return Err(Error::PayloadError("Error sl651 multi-packet frames are not supported".to_string()));
+        }
+        Ok(Frame {
+            ascii:false,
+            center:raw[2],
+            station:to_hex(&raw[3..8]),
+            password:u16::from_be_bytes([raw[8], raw[9]]),
+            function:raw[10],
+            body:raw[HEX_HEADER..total-HEX_TRAILER].to_vec(),
+        })
+    }
+
+    fn decode_ascii(raw:&[u8]) -> Result<Frame,Error> {
+        let total = raw.len();
+        if total < ASCII_HEADER+ASCII_TRAILER {
+            return Err(Error::PayloadError("Error sl651 frame too short".to_string()));
+        }
+        let crc = hex_field(&raw[total-4..], u16::from_str_radix)?;
+        if crc != crc16(&raw[..total-4]) {
+            return Err(Error::PayloadError(format!("Error sl651 crc {:04X}", crc)));
+        }
+        if raw[19] != b'0' {
+            return Err(Error::PayloadError("Error sl651 downlink frame".to_string()));
+        }
+        if raw[23] == SYN {
+            return Err(Error::PayloadError("Error sl651 multi-packet frames are not supported".to_string()));
+        }
+        let station = str::from_utf8(&raw[3..13])
+            .map_err(|e| Error::PayloadError(format!("Error parse sl651 station to {}", e.to_string())))?;
+        Ok(Frame {
+            ascii:true,
+            center:hex_field(&raw[1..3], u8::from_str_radix)?,
+            station:station.to_string(),
+            password:hex_field(&raw[13..17], u16::from_str_radix)?,
+            function:hex_field(&raw[17..19], u8::from_str_radix)?,
+            body:raw[ASCII_HEADER..total-ASCII_TRAILER].to_vec(),
+        })
+    }
+
+    pub fn is_report(&self) -> bool {
+        match self.function {
+            FN_TEST | FN_TIMED | FN_ADDED | FN_HOURLY => true,
+            _ => false,
+        }
+    }
+
+    pub fn report(&self) -> Result<Report,Error> {
+        if self.ascii {
+            parse_ascii_body(&self.body)
+        } else {
+            parse_hex_body(&self.body)
+        }
+    }
+
+    // confirmation sent back to the station, echoing the serial number with our clock
+    pub fn ack(&self,serial:u16,now:i64) -> Vec<u8> {
+        let local = NaiveDateTime::from_timestamp(now+8*3600, 0);
+        let time_parts = [
+            local.year() as u32 % 100, local.month(), local.day(),
+            local.hour(), local.minute(), local.second(),
+        ];
+        let mut out = vec![];
+        if self.ascii {
+            out.push(SOH_ASCII);
+            out.extend_from_slice(self.station.as_bytes());
+            out.extend_from_slice(format!("{:02X}{:04X}{:02X}8010", self.center, self.password, self.function).as_bytes());
+            out.push(STX);
+            out.extend_from_slice(format!("{:04X}", serial).as_bytes());
+            for p in time_parts.iter() {
+                out.extend_from_slice(format!("{:02}", p).as_bytes());
+            }
+            out.push(EOT);
+            let crc = crc16(&out);
+            out.extend_from_slice(format!("{:04X}", crc).as_bytes());
+        } else {
+            out.extend_from_slice(&SOH_HEX);
+            out.extend_from_slice(&from_hex(&self.station).unwrap_or_default());
+            out.push(self.center);
+            out.extend_from_slice(&self.password.to_be_bytes());
+            out.push(self.function);
+            out.extend_from_slice(&[0x80, 0x08, STX]);
+            out.extend_from_slice(&serial.to_be_bytes());
+            for p in time_parts.iter() {
+                out.push(to_bcd(*p));
+            }
+            out.push(EOT);
+            let crc = crc16(&out);
+            out.extend_from_slice(&crc.to_be_bytes());
+        }
+        out
+    }
+}
+
+fn parse_hex_body(body:&[u8]) -> Result<Report,Error> {
+    if body.len() < 8 {
+        return Err(Error::PayloadError("Error sl651 body too short".to_string()));
+    }
+    let serial = u16::from_be_bytes([body[0], body[1]]);
+    let send_time = bcd_time(&body[2..8]);
+    let mut observed = send_time;
+    let mut elements = vec![];
+    let mut i = 8;
+    while i+1 < body.len() {
+        let (id, def) = (body[i], body[i+1]);
+        match id {
+            // ST: station address and category
+            0xF1 if def == 0xF1 => i += 8,
+            // TT: observation time of the elements that follow
+            0xF0 if def == 0xF0 => {
+                observed = body.get(i+2..i+7).and_then(bcd_time).or(send_time);
+                i += 7;
+            }
+            // DRP: 5-minute rainfall in 0.1 mm
+            0xF4 => {
+                let values = body.get(i+2..i+14).unwrap_or(&[])
+                    .iter()
+                    .map(|b| if *b == 0xFF { None } else { Some(*b as f32*0.1) })
+                    .collect();
+                elements.extend(five_minute_elements("DRP", values, observed));
+                i += 14;
+            }
+            // DRZ1-DRZ8: 5-minute relative water level in 0.01 m
+            0xF5..=0xFC => {
+                let values = body.get(i+2..i+26).unwrap_or(&[])
+                    .chunks(2)
+                    .map(|c| match c {
+                        [0xFF, 0xFF] => None,
+                        [h, l] => Some(u16::from_be_bytes([*h, *l]) as f32*0.01),
+                        _ => None,
+                    })
+                    .collect();
+                let name = format!("DRZ{}", id-0xF4);
+                elements.extend(five_minute_elements(&name, values, observed));
+                i += 26;
+            }
+            _ => {
+                let len = (def >> 3) as usize;
+                let decimals = def & 0x07;
+                if let Some(value) = body.get(i+2..i+2+len).and_then(|v| bcd_value(v, decimals)) {
+                    elements.push(Element {
+                        name:element_name(id),
+                        value,
+                        time:observed,
+                    });
+                }
+                i += 2+len;
+            }
+        }
+    }
+    Ok(Report {
+        serial,
+        send_time,
+        elements,
+    })
+}
+
+fn parse_ascii_body(body:&[u8]) -> Result<Report,Error> {
+    // ASCII frames are plain ASCII, which also keeps the byte offsets below on char boundaries
+    if !body.is_ascii() {
+        return Err(Error::PayloadError("Error parse sl651 body to non-ASCII bytes".to_string()));
+    }
+    let text = str::from_utf8(body)
+        .map_err(|e| Error::PayloadError(format!("Error parse sl651 body to {}", e.to_string())))?;
+    if text.len() < 16 {
+        return Err(Error::PayloadError("Error sl651 body too short".to_string()));
+    }
+    let serial = hex_field(&body[0..4], u16::from_str_radix)?;
+    let send_time = ascii_time(&text[4..16]);
+    let mut observed = send_time;
+    let mut elements = vec![];
+    let mut tokens = text[16..].split_whitespace();
+    while let Some(token) = tokens.next() {
+        match token {
+            "ST" => {
+                tokens.next();
+                tokens.next();
+            }
+            "TT" => observed = tokens.next().and_then(ascii_time).or(send_time),
+            "DRP" => {
+                let values = tokens.next().and_then(from_hex).unwrap_or_default()
+                    .into_iter()
+                    .map(|b| if b == 0xFF { None } else { Some(b as f32*0.1) })
+                    .collect();
+                elements.extend(five_minute_elements("DRP", values, observed));
+            }
+            name if name.starts_with("DRZ") => {
+                let values = tokens.next().and_then(from_hex).unwrap_or_default()
+                    .chunks(2)
+                    .map(|c| match c {
+                        [0xFF, 0xFF] => None,
+                        [h, l] => Some(u16::from_be_bytes([*h, *l]) as f32*0.01),
+                        _ => None,
+                    })
+                    .collect();
+                elements.extend(five_minute_elements(name, values, observed));
+            }
+            name => {
+                if let Some(value) = tokens.next().and_then(|v| v.parse::<f32>().ok()) {
+                    elements.push(Element {
+                        name:name.to_string(),
+                        value,
+                        time:observed,
+                    });
+                }
+            }
+        }
+    }
+    Ok(Report {
+        serial,
+        send_time,
+        elements,
+    })
+}
+
+fn handle_frame(stream:&mut TcpStream,raw:&[u8],pool:&PgPool,ingestor:&Ingestor) -> Result<usize,Error> {
+    let received = Utc::now().timestamp();
+    let conn = pool.get().map_err(|e| Error::DatabaseError(e.to_string()))?;
+    let raw_text = if raw.first() == Some(&SOH_ASCII) {
+        String::from_utf8_lossy(raw).to_string()
+    } else {
+        to_hex(raw)
+    };
+    let frame = match Frame::decode(raw) {
+        Ok(f) => f,
+        Err(e) => {
+            let source = format!("sl651/{}", stream.peer_addr().map(|a| a.to_string()).unwrap_or_default());
+            ingest::reject_message(&conn, &source, &raw_text, &e.to_string(), received);
+            return Err(e);
+        }
+    };
+    let source = format!("sl651/{}", frame.station);
+    if let Err(e) = models::new_raw_message(&conn, &source, &raw_text, received) {
+        println!("{}", e.to_string());
+    }
+    if frame.function == FN_KEEPALIVE {
+        return Ok(0);
+    }
+    if !frame.is_report() {
+        ingest::reject_message(&conn, &source, &raw_text, &format!("unsupported function {:02X}", frame.function), received);
+        return Ok(0);
+    }
+    let report = match frame.report() {
+        Ok(r) => r,
+        Err(e) => {
+            ingest::reject_message(&conn, &source, &raw_text, &e.to_string(), received);
+            return Err(e);
+        }
+    };
+    // test reports are acknowledged but not stored
+    if frame.function == FN_TEST {
+        send_ack(stream, &frame, report.serial, received)?;
+        return Ok(0);
+    }
+    let dev_id = match models::get_id_by_sl651_address(&conn, &frame.station) {
+        Ok(d) => d,
+        Err(_) => {
+            // kept as a rejected message, a retransmission would be rejected again
+            let reason = format!("unknown station {}", frame.station);
+            ingest::reject_message(&conn, &source, &raw_text, &reason, received);
+            send_ack(stream, &frame, report.serial, received)?;
+            return Err(Error::PayloadError(reason));
+        }
+    };
+    let points:Vec<DataPoint> = report.elements.iter()
+        .map(|e| DataPoint::new(&e.name, e.value, e.time))
+        .collect();
+    let ingested = ingestor.ingest_points(&conn, dev_id, &points, report.send_time, Some(&raw_text), received);
+    // an acknowledged report is dropped by the station; without the ack it retransmits
+    // and the points already stored are dropped as duplicates
+    if ingested.failed > 0 {
+        return Err(Error::DatabaseError(format!("{} points of station {} not stored, report {} not acknowledged",
+            ingested.failed, frame.station, report.serial)));
+    }
+    send_ack(stream, &frame, report.serial, received)?;
+    Ok(ingested.stored)
+}
+
+fn send_ack(stream:&mut TcpStream,frame:&Frame,serial:u16,received:i64) -> Result<(),Error> {
+    stream.write_all(&frame.ack(serial, received))
+        .map_err(|e| Error::PayloadError(format!("Error send sl651 ack to {}", e.to_string())))
+}
+
+fn handle_connection(mut stream:TcpStream,pool:PgPool,ingestor:Arc<Ingestor>) {
+    let peer = stream.peer_addr().map(|a| a.to_string()).unwrap_or_default();
+    println!("Station connected from {}", peer);
+    stream.set_read_timeout(Some(Duration::from_secs(READ_TIMEOUT_SECS))).ok();
+    let mut buf:Vec<u8> = vec![];
+    let mut chunk = [0u8;1024];
+    loop {
+        let n = match stream.read(&mut chunk) {
+            Ok(0) => break,
+            Ok(n) => n,
+            Err(_) => break,
+        };
+        buf.extend_from_slice(&chunk[..n]);
+        while let Some(raw) = take_frame(&mut buf) {
+            match handle_frame(&mut stream, &raw, &pool, &ingestor) {
+                Ok(stored) => println!("Frame from {} stored {} points", peer, stored),
+                Err(e) => println!("Frame from {} failed: {}", peer, e.to_string()),
+            }
+        }
+        if buf.len() > MAX_BUFFER {
+            println!("Frame from {} exceeds {} bytes, {} bytes dropped", peer, MAX_BUFFER, buf.len());
+            buf.clear();
+        }
+    }
+    println!("Station disconnected from {}", peer);
+}
+
+pub fn run_sl651_listener(pool:PgPool) -> Result<(),Error> {
+    let config = Config::new();
+    let ingestor = Arc::new(Ingestor::new(&config));
+    let listener = TcpListener::bind(config.sl651_listen())
+        .map_err(|e| Error::PayloadError(format!("Error bind {} to {}", config.sl651_listen(), e.to_string())))?;
+    println!("SL651 listening on {}", config.sl651_listen());
+    for stream in listener.incoming() {
+        match stream {
+            Ok(stream) => {
+                let pool = pool.clone();
+                let ingestor = ingestor.clone();
+                thread::spawn(move || handle_connection(stream, pool, ingestor));
+            }
+            Err(e) => println!("Accept failed: {}", e),
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hex_frame(function:u8,body:&[u8]) -> Vec<u8> {
+        let mut out = SOH_HEX.to_vec();
+        out.push(0x01);
+        out.extend_from_slice(&[0x00, 0x12, 0x34, 0x56, 0x78]);
+        out.extend_from_slice(&[0x12, 0x34]);
+        out.push(function);
+        out.extend_from_slice(&[(body.len() >> 8) as u8, body.len() as u8]);
+        out.push(STX);
+        out.extend_from_slice(body);
+        out.push(ETX);
+        let crc = crc16(&out);
+        out.extend_from_slice(&crc.to_be_bytes());
+        out
+    }
+
+    fn ascii_frame(body:&str) -> Vec<u8> {
+        let mut out = vec![SOH_ASCII];
+        out.extend_from_slice(format!("0100123456781234340{:03X}", body.len()).as_bytes());
+        out.push(STX);
+        out.extend_from_slice(body.as_bytes());
+        out.push(ETX);
+        let crc = crc16(&out);
+        out.extend_from_slice(format!("{:04X}", crc).as_bytes());
+        out
+    }
+
+    // serial 1 sent 2021-06-01 08:00:00, observed 08:00, PT 123.4 mm
+    fn hourly_body() -> Vec<u8> {
+        let mut body = vec![0x00, 0x01, 0x21, 0x06, 0x01, 0x08, 0x00, 0x00];
+        body.extend_from_slice(&[0xF1, 0xF1, 0x00, 0x12, 0x34, 0x56, 0x78, 0x50]);
+        body.extend_from_slice(&[0xF0, 0xF0, 0x21, 0x06, 0x01, 0x08, 0x00]);
+        body.extend_from_slice(&[0x26, 0x19, 0x00, 0x12, 0x34]);
+        body
+    }
+
+    #[test]
+    fn decodes_hex_report() {
+        let frame = Frame::decode(&hex_frame(FN_HOURLY, &hourly_body())).unwrap();
+        assert!(!frame.ascii);
+        assert_eq!(frame.station, "0012345678");
+        assert_eq!(frame.function, FN_HOURLY);
+        let report = frame.report().unwrap();
+        assert_eq!(report.serial, 1);
+        assert_eq!(report.send_time, Some(1622505600));
+        assert_eq!(report.elements.len(), 1);
+        assert_eq!(report.elements[0].name, "PT");
+        assert!((report.elements[0].value - 123.4).abs() < 1e-4);
+        assert_eq!(report.elements[0].time, Some(1622505600));
+    }
+
+    #[test]
+    fn decodes_ascii_report() {
+        let frame = Frame::decode(&ascii_frame("0001210601080000 PT 12.5 Z 1.25")).unwrap();
+        assert!(frame.ascii);
+        assert_eq!(frame.station, "0012345678");
+        let report = frame.report().unwrap();
+        assert_eq!(report.serial, 1);
+        let values:Vec<(&str, f32)> = report.elements.iter().map(|e| (e.name.as_str(), e.value)).collect();
+        assert_eq!(values, vec![("PT", 12.5), ("Z", 1.25)]);
+    }
+
+    #[test]
+    fn rejects_bad_crc() {
+        let mut raw = hex_frame(FN_HOURLY, &hourly_body());
+        let last = raw.len()-1;
+        raw[last] ^= 0xFF;
+        assert!(Frame::decode(&raw).is_err());
+        let mut raw = ascii_frame("0001210601080000 PT 12.5");
+        raw[30] = b'9';
+        assert!(Frame::decode(&raw).is_err());
+    }
+
+    #[test]
+    fn rejects_non_ascii_body() {
+        // a multibyte char across the time field must not panic
+        assert!(parse_ascii_body("000121060108000\u{e9} PT 1".as_bytes()).is_err());
+    }
+
+    #[test]
+    fn takes_frame_split_across_reads() {
+        let raw = hex_frame(FN_HOURLY, &hourly_body());
+        let mut buf = vec![0x00, 0x55];
+        buf.extend_from_slice(&raw[..10]);
+        assert_eq!(take_frame(&mut buf), None);
+        assert_eq!(buf, raw[..10].to_vec());
+        buf.extend_from_slice(&raw[10..]);
+        buf.extend_from_slice(&raw[..5]);
+        assert_eq!(take_frame(&mut buf), Some(raw.clone()));
+        assert_eq!(buf, raw[..5].to_vec());
+    }
+
+    #[test]
+    fn resyncs_after_bad_length_field() {
+        let raw = hex_frame(FN_HOURLY, &hourly_body());
+        let mut buf = vec![SOH_ASCII];
+        buf.extend_from_slice(b"0100123456781234340ZZZ");
+        buf.extend_from_slice(&raw);
+        assert_eq!(take_frame(&mut buf), Some(raw));
+        assert!(buf.is_empty());
+    }
+
+    #[test]
+    fn resyncs_after_bad_end_flag() {
+        let raw = hex_frame(FN_HOURLY, &hourly_body());
+        let mut broken = raw.clone();
+        let flag = broken.len()-HEX_TRAILER;
+        broken[flag] = 0x00;
+        let mut buf = broken;
+        buf.extend_from_slice(&raw);
+        assert_eq!(take_frame(&mut buf), Some(raw));
+    }
+
+    #[test]
+    fn drops_garbage_without_frame_start() {
+        let mut buf = vec![0x30, 0x31, 0x32, 0x7E];
+        assert_eq!(take_frame(&mut buf), None);
+        assert_eq!(buf, vec![0x7E]);
+    }
+}
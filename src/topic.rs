@@ -0,0 +1,159 @@
+use std::collections::HashMap;
+
+use super::error::Error;
+
+const DEVICE_ID:&str = "device_id";
+
+#[derive(Debug, Clone, PartialEq)]
+enum Level {
+    Literal(String),
+    Placeholder(String),
+    SingleWildcard,
+    MultiWildcard,
+}
+
+// a topic pattern like "site/{region}/{device_id}/up", each {name} stands for one topic level
+#[derive(Debug, Clone)]
+pub struct TopicTemplate {
+    template:String,
+    levels:Vec<Level>,
+}
+
+impl TopicTemplate {
+    pub fn parse(template:&str) -> Result<TopicTemplate,Error> {
+        let parts:Vec<&str> = template.split('/').collect();
+        let mut levels = vec![];
+        for (i, part) in parts.iter().enumerate() {
+            let level = match *part {
+                "+" => Level::SingleWildcard,
+                "#" if i == parts.len()-1 => Level::MultiWildcard,
+                p if p.starts_with('{') && p.ends_with('}') && p.len() > 2 => Level::Placeholder(p[1..p.len()-1].to_string()),
+                p if p.contains(&['{', '}', '+', '#'][..]) => {
+                    return Err(Error::MqttError(format!("Error topic template {}: {} must be a whole level", template, p)));
+                }
+                p => Level::Literal(p.to_string()),
+            };
+            levels.push(level);
+        }
+        if !levels.contains(&Level::Placeholder(DEVICE_ID.to_string())) {
+            return Err(Error::MqttError(format!("Error topic template {}: no {{device_id}}", template)));
+        }
+        Ok(TopicTemplate {
+            template:template.to_string(),
+            levels,
+        })
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.template
+    }
+
+    // the subscription filter of one device, other placeholders become +
+    pub fn subscription(&self,device_key:&str) -> String {
+        self.levels.iter().map(|level| match level {
+            Level::Literal(l) => l.as_str(),
+            Level::Placeholder(name) if name == DEVICE_ID => device_key,
+            Level::Placeholder(_) | Level::SingleWildcard => "+",
+            Level::MultiWildcard => "#",
+        }).collect::<Vec<&str>>().join("/")
+    }
+
+    // placeholder values when the topic matches, with MQTT wildcard semantics for + and #
+    pub fn captures<'a>(&self,topic:&'a str) -> Option<HashMap<&str,&'a str>> {
+        let parts:Vec<&str> = topic.split('/').collect();
+        let mut values = HashMap::new();
+        for (i, level) in self.levels.iter().enumerate() {
+            match level {
+                Level::MultiWildcard => return Some(values),
+                _ if i >= parts.len() => return None,
+                Level::Literal(l) if l != parts[i] => return None,
+                Level::Literal(_) | Level::SingleWildcard => {}
+                Level::Placeholder(name) => {
+                    if parts[i].is_empty() {
+                        return None;
+                    }
+                    values.insert(name.as_str(), parts[i]);
+                }
+            }
+        }
+        if parts.len() != self.levels.len() {
+            return None;
+        }
+        Some(values)
+    }
+
+    pub fn device_key<'a>(&self,topic:&'a str) -> Option<&'a str> {
+        self.captures(topic).and_then(|c| c.get(DEVICE_ID).cloned())
+    }
+}
+
+// device ids are used as a topic level, so they can't hold separators or wildcards
+pub fn valid_device_key(device_key:&str) -> bool {
+    !device_key.is_empty() && !device_key.contains(&['/', '+', '#'][..])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn default_template() {
+        let t = TopicTemplate::parse("$USR/DevJsonTx/{device_id}").unwrap();
+        assert_eq!(t.subscription("d1"), "$USR/DevJsonTx/d1");
+        assert_eq!(t.device_key("$USR/DevJsonTx/d1"), Some("d1"));
+        assert_eq!(t.device_key("$USR/DevJsonRx/d1"), None);
+    }
+
+    #[test]
+    fn short_and_long_topics_do_not_match() {
+        let t = TopicTemplate::parse("$USR/DevJsonTx/{device_id}").unwrap();
+        assert_eq!(t.device_key("$USR/DevJsonTx"), None);
+        assert_eq!(t.device_key("$USR/DevJsonTx/"), None);
+        assert_eq!(t.device_key("$USR/DevJsonTx/d1/extra"), None);
+    }
+
+    #[test]
+    fn other_placeholders_are_captured() {
+        let t = TopicTemplate::parse("site/{region}/{device_id}/up").unwrap();
+        assert_eq!(t.subscription("d1"), "site/+/d1/up");
+        let captures = t.captures("site/north/d1/up").unwrap();
+        assert_eq!(captures.get("region"), Some(&"north"));
+        assert_eq!(captures.get("device_id"), Some(&"d1"));
+        assert_eq!(t.captures("site/north/d1/down"), None);
+    }
+
+    #[test]
+    fn single_level_wildcard() {
+        let t = TopicTemplate::parse("+/{device_id}/up").unwrap();
+        assert_eq!(t.subscription("d1"), "+/d1/up");
+        assert_eq!(t.device_key("gw7/d1/up"), Some("d1"));
+        assert_eq!(t.device_key("gw7/x/d1/up"), None);
+    }
+
+    #[test]
+    fn multi_level_wildcard() {
+        let t = TopicTemplate::parse("data/{device_id}/#").unwrap();
+        assert_eq!(t.subscription("d1"), "data/d1/#");
+        assert_eq!(t.device_key("data/d1/a/b/c"), Some("d1"));
+        // # also matches the parent level
+        assert_eq!(t.device_key("data/d1"), Some("d1"));
+        assert_eq!(t.device_key("data"), None);
+    }
+
+    #[test]
+    fn rejects_bad_templates() {
+        assert!(TopicTemplate::parse("data/{region}").is_err());
+        assert!(TopicTemplate::parse("data/#/{device_id}").is_err());
+        assert!(TopicTemplate::parse("data/dev{device_id}").is_err());
+        assert!(TopicTemplate::parse("data/{}/{device_id}").is_err());
+    }
+
+    #[test]
+    fn device_keys_are_single_levels() {
+        assert!(valid_device_key("d1"));
+        assert!(!valid_device_key(""));
+        assert!(!valid_device_key("a/b"));
+        assert!(!valid_device_key("a+"));
+        assert!(!valid_device_key("#"));
+    }
+}
@@ -1,17 +1,31 @@
 use rocket_contrib::databases::diesel::PgConnection;
 use rocket_contrib::serve::StaticFiles;
 use rocket_contrib::json::Json;
-use serde::Serialize;
+use rocket::http::Status;
+use rocket::request::{self, FromRequest, Request};
+use rocket::response::{self, Responder};
+use rocket::{Outcome, State};
+use serde::{Deserialize, Serialize};
+use serde_json;
+use chrono::prelude::*;
+use super::config::Config;
 use super::error::Error;
+use super::ingest::{Ingestor, IngestReport, PayloadFormat};
 use super::models;
+use super::replay;
+use super::runoff;
 
 
 #[database("mountain_torrents")]
 pub struct DbConn(PgConnection);
 
 pub fn run() {
+    let config = Config::new();
     rocket::ignite()    
         .attach(DbConn::fairing())
+        .manage(Ingestor::new(&config))
+        .manage(IngestToken(config.ingest_token().map(|t| t.to_string())))
+        .manage(AdminToken(config.admin_token().map(|t| t.to_string())))
         .mount("/api", routes![
             mt_current,
             half_rain,
@@ -22,11 +36,72 @@ pub fn run() {
             one_half_rain,
             two_rain,
             three_rain,
+            dead_letters,
+            ingest,
+            device_status,
+            depth_channels,
+            half_health,
+            device_health,
+            model_parameters,
+            save_model_parameters,
+            recompute,
+            model_state,
+            restore_model_state,
         ])
         .mount("/", StaticFiles::from("dist"))        
         .launch();
 }
 
+impl<'r> Responder<'r> for Error {
+    fn respond_to(self, _req: &Request) -> response::Result<'r> {
+        println!("{}", self.to_string());
+        match self {
+            Error::PayloadError(_) => Err(Status::BadRequest),
+            _ => Err(Status::InternalServerError),
+        }
+    }
+}
+
+pub struct IngestToken(Option<String>);
+
+// gateways posting readings must send the configured ingest_token in X-Api-Key
+pub struct ApiKey;
+
+impl<'a, 'r> FromRequest<'a, 'r> for ApiKey {
+    type Error = ();
+
+    fn from_request(request: &'a Request<'r>) -> request::Outcome<ApiKey, ()> {
+        let token = match request.guard::<State<IngestToken>>() {
+            Outcome::Success(t) => t,
+            _ => return Outcome::Failure((Status::InternalServerError, ())),
+        };
+        match (token.0.as_ref(), request.headers().get_one("X-Api-Key")) {
+            (Some(expected), Some(key)) if expected.as_str() == key => Outcome::Success(ApiKey),
+            _ => Outcome::Failure((Status::Unauthorized, ())),
+        }
+    }
+}
+
+pub struct AdminToken(Option<String>);
+
+// callers of admin endpoints must send the configured admin_token in X-Admin-Key
+pub struct AdminKey;
+
+impl<'a, 'r> FromRequest<'a, 'r> for AdminKey {
+    type Error = ();
+
+    fn from_request(request: &'a Request<'r>) -> request::Outcome<AdminKey, ()> {
+        let token = match request.guard::<State<AdminToken>>() {
+            Outcome::Success(t) => t,
+            _ => return Outcome::Failure((Status::InternalServerError, ())),
+        };
+        match (token.0.as_ref(), request.headers().get_one("X-Admin-Key")) {
+            (Some(expected), Some(key)) if expected.as_str() == key => Outcome::Success(AdminKey),
+            _ => Outcome::Failure((Status::Unauthorized, ())),
+        }
+    }
+}
+
 #[derive(Serialize)]
 pub struct MTRow {
     id:i32,
@@ -55,6 +130,96 @@ pub struct ChartData {
     describe:String,
 }
 
+#[derive(Serialize)]
+pub struct DeadLetterRow {
+    id:i32,
+    topic:String,
+    payload:String,
+    reason:String,
+    receive_time:String,
+}
+
+#[derive(Serialize)]
+pub struct ChannelChart {
+    channel:String,
+    primary:bool,
+    values:Vec<Option<f32>>,
+    times:Vec<String>,
+}
+
+#[derive(Serialize)]
+pub struct HealthRow {
+    id:i32,
+    metric:String,
+    value:f32,
+    alarm:bool,
+    time:String,
+}
+
+#[derive(Serialize)]
+pub struct ModelParameterRow {
+    id:i32,
+    stored:bool,
+    runoff_model:String,
+    catchment_area:f32,
+    storage_capacity:f32,
+    loss_rate:f32,
+    reservoir_a:f32,
+    reservoir_b:f32,
+    curve_number:f32,
+    runoff_coefficient:f32,
+}
+
+// catchment_area left out uses devices.rainfall_area; runoff_model defaults to reservoir
+#[derive(Deserialize)]
+pub struct ModelParameterForm {
+    runoff_model:Option<String>,
+    catchment_area:Option<f32>,
+    storage_capacity:Option<f32>,
+    loss_rate:Option<f32>,
+    reservoir_a:Option<f32>,
+    reservoir_b:Option<f32>,
+    curve_number:Option<f32>,
+    runoff_coefficient:Option<f32>,
+}
+
+#[derive(Serialize)]
+pub struct CheckpointRow {
+    id:i64,
+    storage:f32,
+    wi:f32,
+    state_time:String,
+    calculation_id:Option<i32>,
+    reason:String,
+    restored_from:Option<i64>,
+    create_time:String,
+    model:String,
+}
+
+#[derive(Serialize)]
+pub struct ModelStateRow {
+    id:i32,
+    model:Option<String>,
+    storage:Option<f32>,
+    wi:Option<f32>,
+    state_time:Option<String>,
+    calculation_id:Option<i32>,
+    checkpoints:Vec<CheckpointRow>,
+}
+
+#[derive(Serialize)]
+pub struct DeviceStatusRow {
+    id:i32,
+    name:String,
+    region:String,
+    device_id:String,
+    online:bool,
+    last_seen:Option<String>,
+    last_payload_size:Option<i32>,
+    last_values:serde_json::Value,
+    offline_reason:Option<String>,
+}
+
 #[get("/mt_current")]
 pub fn mt_current(conn:DbConn) -> Result<Json<Vec<MTRow>>,Error> {
     let dev_ids = models::device_ids(&conn)?;
@@ -107,15 +272,18 @@ pub fn half_rain(conn:DbConn,dev_id:i32) -> Result<Json<ChartData>,Error> {
     Ok(Json(data))
 }
 
-#[get("/half_depth?<dev_id>")]
-pub fn half_depth(conn:DbConn,dev_id:i32) -> Result<Json<ChartData>,Error> {
+#[get("/half_depth?<dev_id>&<channel>")]
+pub fn half_depth(conn:DbConn,dev_id:i32,channel:Option<String>) -> Result<Json<ChartData>,Error> {
     
-    let half_depths = models::a_depths_by_half(&conn, dev_id)?;
+    let half_depths = models::a_depths_by_half(&conn, dev_id, channel.as_ref().map(|c| c.as_str()))?;
     
     let data = ChartData {
         values:half_depths.0,
         times:half_depths.1,
-        describe:"水深".to_string(),
+        describe:match channel {
+            Some(c) => format!("水深({})", c),
+            None => "水深".to_string(),
+        },
     };
     
     Ok(Json(data))
@@ -200,4 +368,202 @@ pub fn three_rain(conn:DbConn,dev_id:i32) -> Result<Json<ChartData>,Error> {
     };
     
     Ok(Json(data))
-}
\ No newline at end of file
+}
+
+#[get("/dead_letters?<limit>")]
+pub fn dead_letters(conn:DbConn,limit:Option<i64>) -> Result<Json<Vec<DeadLetterRow>>,Error> {
+    
+    let letters = models::recent_dead_letters(&conn, limit.unwrap_or(100))?;
+    
+    let rows = letters.into_iter().map(|l| DeadLetterRow {
+        id:l.id,
+        topic:l.topic,
+        payload:l.payload,
+        reason:l.reason,
+        receive_time:NaiveDateTime::from_timestamp(l.receive_time.timestamp()+8*3600, 0).to_string(),
+    }).collect();
+    
+    Ok(Json(rows))
+}
+
+#[post("/ingest/<device_id>", data = "<payload>")]
+pub fn ingest(conn:DbConn,_key:ApiKey,ingestor:State<Ingestor>,device_id:String,payload:String) -> Result<Json<IngestReport>,Error> {
+    let received = Utc::now().timestamp();
+    let source = format!("http/{}", device_id);
+    let report = ingestor.ingest(&conn, &source, &device_id, &payload, PayloadFormat::Json, received)?;
+    Ok(Json(report))
+}
+
+#[get("/devices/status")]
+pub fn device_status(conn:DbConn) -> Result<Json<Vec<DeviceStatusRow>>,Error> {
+    
+    let statuses = models::device_statuses(&conn)?;
+    
+    let rows = statuses.into_iter().map(|(dev, status)| DeviceStatusRow {
+        id:dev.id,
+        name:dev.name,
+        region:dev.region,
+        device_id:dev.device_id,
+        online:status.as_ref().map(|s| s.online).unwrap_or(false),
+        last_seen:status.as_ref().map(|s| NaiveDateTime::from_timestamp(s.last_seen.timestamp()+8*3600, 0).to_string()),
+        last_payload_size:status.as_ref().and_then(|s| s.last_payload_size),
+        last_values:status.as_ref()
+            .and_then(|s| serde_json::from_str(&s.last_values).ok())
+            .unwrap_or(serde_json::Value::Null),
+        offline_reason:status.and_then(|s| s.offline_reason),
+    }).collect();
+    
+    Ok(Json(rows))
+}
+
+#[get("/depth_channels?<dev_id>")]
+pub fn depth_channels(conn:DbConn,dev_id:i32) -> Result<Json<Vec<ChannelChart>>,Error> {
+    
+    let device = models::get_device(&conn, dev_id)?;
+    let channels = models::depth_channels(&conn, dev_id)?;
+    
+    let mut charts:Vec<ChannelChart> = vec![];
+    for channel in channels {
+        let half_depths = models::a_depths_by_half(&conn, dev_id, Some(&channel))?;
+        charts.push(ChannelChart {
+            primary:device.primary_channel.as_ref() == Some(&channel),
+            channel,
+            values:half_depths.0,
+            times:half_depths.1,
+        });
+    }
+    
+    Ok(Json(charts))
+}
+
+#[get("/half_health?<dev_id>&<metric>")]
+pub fn half_health(conn:DbConn,dev_id:i32,metric:String) -> Result<Json<ChartData>,Error> {
+    
+    let half_health = models::a_health_by_half(&conn, dev_id, &metric)?;
+    
+    let describe = match metric.as_str() {
+        "voltage" => "电池电压",
+        "solar" => "太阳能充电",
+        "signal" => "信号强度",
+        "temperature" => "机箱温度",
+        other => other,
+    };
+    let data = ChartData {
+        values:half_health.0,
+        times:half_health.1,
+        describe:describe.to_string(),
+    };
+    
+    Ok(Json(data))
+}
+
+#[get("/devices/health")]
+pub fn device_health(conn:DbConn) -> Result<Json<Vec<HealthRow>>,Error> {
+    
+    let latest = models::latest_device_health(&conn)?;
+    
+    let rows = latest.into_iter().map(|h| HealthRow {
+        id:h.device_id,
+        value:h.value_def(),
+        metric:h.metric,
+        alarm:h.alarm,
+        time:NaiveDateTime::from_timestamp(h.create_time.timestamp()+8*3600, 0).to_string(),
+    }).collect();
+    
+    Ok(Json(rows))
+}
+
+#[get("/model_parameters?<dev_id>")]
+pub fn model_parameters(conn:DbConn,dev_id:i32) -> Result<Json<ModelParameterRow>,Error> {
+    
+    let (params, stored) = models::runoff_params(&conn, dev_id)?;
+    
+    Ok(Json(ModelParameterRow {
+        id:dev_id,
+        stored,
+        runoff_model:params.runoff_model,
+        catchment_area:params.catchment_area,
+        storage_capacity:params.storage_capacity,
+        loss_rate:params.loss_rate,
+        reservoir_a:params.reservoir_a,
+        reservoir_b:params.reservoir_b,
+        curve_number:params.curve_number,
+        runoff_coefficient:params.runoff_coefficient,
+    }))
+}
+
+#[post("/model_parameters/<dev_id>", data = "<form>")]
+pub fn save_model_parameters(conn:DbConn,_key:AdminKey,dev_id:i32,form:Json<ModelParameterForm>) -> Result<Json<ModelParameterRow>,Error> {
+    let form = form.into_inner();
+    let positive = |v:Option<f32>| v.map(|v| v > 0.0).unwrap_or(true);
+    let non_negative = |v:Option<f32>| v.map(|v| v >= 0.0).unwrap_or(true);
+    if !positive(form.catchment_area) || !positive(form.reservoir_a)
+        || !non_negative(form.storage_capacity) || !non_negative(form.loss_rate) || !non_negative(form.reservoir_b) {
+        return Err(Error::PayloadError("model parameters out of range".to_string()));
+    }
+    models::get_device(&conn, dev_id)?;
+    // omitted fields keep the device's current parameters
+    let (current, _) = models::runoff_params(&conn, dev_id)?;
+    let device_area = form.catchment_area.is_none() && !models::catchment_area_stored(&conn, dev_id)?;
+    let params = models::RunoffParams {
+        runoff_model:form.runoff_model.unwrap_or(current.runoff_model),
+        catchment_area:form.catchment_area.unwrap_or(current.catchment_area),
+        storage_capacity:form.storage_capacity.unwrap_or(current.storage_capacity),
+        loss_rate:form.loss_rate.unwrap_or(current.loss_rate),
+        reservoir_a:form.reservoir_a.unwrap_or(current.reservoir_a),
+        reservoir_b:form.reservoir_b.unwrap_or(current.reservoir_b),
+        curve_number:form.curve_number.unwrap_or(current.curve_number),
+        runoff_coefficient:form.runoff_coefficient.unwrap_or(current.runoff_coefficient),
+    };
+    runoff::runoff_model(&params).map_err(|e| Error::PayloadError(e.to_string()))?;
+    models::save_runoff_params(&conn, dev_id, &params, device_area)?;
+    model_parameters(conn, dev_id)
+}
+
+// from and to are "%Y-%m-%d" or "%Y-%m-%d %H:%M:%S" in UTC+8, to defaults to now
+#[post("/recompute/<dev_id>?<from>&<to>&<dry_run>")]
+pub fn recompute(conn:DbConn,_key:AdminKey,dev_id:i32,from:String,to:Option<String>,dry_run:Option<bool>) -> Result<Json<models::RecomputeReport>,Error> {
+    let from = replay::parse_bound(&from, false)?;
+    let to = match to {
+        Some(t) => replay::parse_bound(&t, true)?,
+        None => Utc::now().timestamp(),
+    };
+    models::get_device(&conn, dev_id)?;
+    let report = models::recompute_calculations(&conn, dev_id, from, to, dry_run.unwrap_or(false))?;
+    Ok(Json(report))
+}
+
+#[get("/model_state?<dev_id>&<limit>")]
+pub fn model_state(conn:DbConn,dev_id:i32,limit:Option<i64>) -> Result<Json<ModelStateRow>,Error> {
+    
+    let state = models::model_state(&conn, dev_id)?;
+    let checkpoints = models::model_checkpoints(&conn, dev_id, limit.unwrap_or(50))?;
+    
+    let checkpoints = checkpoints.into_iter().map(|c| CheckpointRow {
+        id:c.id,
+        storage:c.storage_def(),
+        wi:c.wi_def(),
+        state_time:NaiveDateTime::from_timestamp(c.state_time.timestamp()+8*3600, 0).to_string(),
+        calculation_id:c.calculation_id,
+        reason:c.reason,
+        restored_from:c.restored_from,
+        create_time:NaiveDateTime::from_timestamp(c.create_time.timestamp()+8*3600, 0).to_string(),
+        model:c.model,
+    }).collect();
+    
+    Ok(Json(ModelStateRow {
+        id:dev_id,
+        model:state.as_ref().map(|st| st.model.clone()),
+        storage:state.as_ref().map(|st| st.storage_def()),
+        wi:state.as_ref().map(|st| st.wi_def()),
+        state_time:state.as_ref().map(|st| NaiveDateTime::from_timestamp(st.state_time.timestamp()+8*3600, 0).to_string()),
+        calculation_id:state.and_then(|st| st.calculation_id),
+        checkpoints,
+    }))
+}
+
+#[post("/model_state/<dev_id>/restore/<checkpoint_id>")]
+pub fn restore_model_state(conn:DbConn,_key:AdminKey,dev_id:i32,checkpoint_id:i64) -> Result<Json<ModelStateRow>,Error> {
+    models::restore_model_state(&conn, dev_id, checkpoint_id)?;
+    model_state(conn, dev_id, Some(50))
+}